@@ -3,9 +3,29 @@
 mod setup;
 mod utils;
 
-use crate::setup::setup;
-use crate::utils::{bob_balance, join_native_pool, mine_block, spawn_miner, upgrade_miner};
-use candid::Principal;
+use crate::setup::{setup, upgrade_bob};
+use crate::utils::{
+    bob_balance, decommission_miner, deposit_icp, get_auto_top_ups, get_blocks, get_deposit,
+    get_events, get_low_cycles_miners, get_miner_health, get_miner_state, get_miner_top_ups,
+    get_owner_overview, get_refunds, get_spawn_request, get_stats, icrc3_get_blocks,
+    join_native_pool, join_pool_with_approval, mine_block, pause_miner, request_refund,
+    resume_miner, resume_spawn, set_auto_top_up, spawn_miner, spawn_miner_from_block,
+    spawn_miner_with_approval, top_up_miner, transfer, transfer_miner, transfer_to,
+    update_miner_settings, upgrade_miner, withdraw_deposit, MinerSettings,
+};
+use bob_minter_v2::auto_top_up::{AutoTopUpPolicy, AutoTopUpStatus};
+use bob_minter_v2::event::EventType;
+use bob_minter_v2::health::MinerStatus;
+use bob_minter_v2::miner::ManageMinerError;
+use bob_minter_v2::payment::SpawnMinerError;
+use bob_minter_v2::refund::{RefundError, RefundStatus};
+use bob_minter_v2::spawn::SpawnStep;
+use bob_minter_v2::top_up::{top_up_account, TopUpMinerError, TopUpStatus};
+use bob_minter_v2::BlockFilter;
+use candid::{Nat, Principal};
+use ic_ledger_types::{AccountIdentifier, Subaccount};
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use pocket_ic::update_candid_as;
 
 // System canister IDs
 
@@ -42,11 +62,23 @@ fn test_spawn_upgrade_miner() {
     mine_block(&pic);
     assert_eq!(bob_balance(&pic, user_id), 120_000_000_000_u64);
 
+    update_miner_settings(
+        &pic,
+        user_id,
+        miner_id,
+        MinerSettings {
+            max_cycles_per_round: Some(20_000_000_000),
+            new_owner: None,
+        },
+    );
+    let miner_state_before_upgrade = get_miner_state(&pic, miner_id);
+
     let miner_cycles_before_upgrade = pic.cycle_balance(miner_id);
     upgrade_miner(&pic, user_id, miner_id);
     let miner_cycles = pic.cycle_balance(miner_id);
     let upgrade_cycles = miner_cycles_before_upgrade - miner_cycles;
     assert!(upgrade_cycles <= 3_000_000_000);
+    assert_eq!(get_miner_state(&pic, miner_id), miner_state_before_upgrade);
 
     assert_eq!(bob_balance(&pic, user_id), 120_000_000_000_u64);
     mine_block(&pic);
@@ -55,6 +87,387 @@ fn test_spawn_upgrade_miner() {
     assert_eq!(bob_balance(&pic, user_id), 240_000_000_000_u64);
 }
 
+#[test]
+fn test_miner_lifecycle() {
+    let user_1 = Principal::from_slice(&[0xFF; 29]);
+    let user_2 = Principal::from_slice(&[0xFE; 29]);
+    let pic = setup(vec![user_1, user_2]);
+
+    let miner_id = spawn_miner(&pic, user_1, 100_000_000);
+    mine_block(&pic);
+    assert_eq!(bob_balance(&pic, user_1), 60_000_000_000_u64);
+
+    pause_miner(&pic, user_1, miner_id);
+    assert!(update_candid_as::<_, (bob_miner_v2::State,)>(
+        &pic,
+        miner_id,
+        Principal::anonymous(),
+        "get_state",
+        ((),),
+    )
+    .is_err());
+    resume_miner(&pic, user_1, miner_id);
+    assert_eq!(get_miner_state(&pic, miner_id).owner, user_1);
+
+    let cycles_to = pic.create_canister();
+    let cycles_before = pic.cycle_balance(cycles_to);
+    assert_eq!(
+        decommission_miner(&pic, user_2, miner_id, Some(cycles_to)),
+        Err(ManageMinerError::NotOwner)
+    );
+    let withdrawn_cycles = decommission_miner(&pic, user_1, miner_id, Some(cycles_to)).unwrap();
+    assert!(withdrawn_cycles > 0);
+    assert!(pic.cycle_balance(cycles_to) > cycles_before);
+    assert_eq!(
+        decommission_miner(&pic, user_1, miner_id, None),
+        Err(ManageMinerError::UnknownMiner)
+    );
+}
+
+#[test]
+fn test_miner_health() {
+    let user_id = Principal::from_slice(&[0xFF; 29]);
+    let pic = setup(vec![user_id]);
+
+    let miner_id = spawn_miner(&pic, user_id, 100_000_000);
+    assert_eq!(get_miner_health(&pic, miner_id), None);
+
+    pic.advance_time(std::time::Duration::from_secs(60 * 60));
+    for _ in 0..5 {
+        pic.tick();
+    }
+    let health = get_miner_health(&pic, miner_id).unwrap();
+    assert_eq!(health.status, Some(MinerStatus::Running));
+    assert!(health.cycles.is_some_and(|cycles| cycles > 0));
+    assert!(health.module_hash.is_some());
+    assert_eq!(health.last_error, None);
+
+    assert!(get_low_cycles_miners(&pic, Some(0)).is_empty());
+    assert_eq!(get_low_cycles_miners(&pic, Some(u128::MAX)), vec![health]);
+}
+
+#[test]
+fn test_top_up_miner() {
+    let user_1 = Principal::from_slice(&[0xFF; 29]);
+    let user_2 = Principal::from_slice(&[0xFE; 29]);
+    let pic = setup(vec![user_1, user_2]);
+
+    let miner_id = spawn_miner(&pic, user_1, 100_000_000);
+    let cycles_before = pic.cycle_balance(miner_id);
+
+    // anyone can top up a miner, with less than the price of a miner
+    let to = AccountIdentifier::from_hex(&top_up_account(miner_id).to_hex()).unwrap();
+    let block_index = transfer_to(&pic, user_2, to, 20_000_000);
+    assert_eq!(
+        top_up_miner(&pic, user_1, miner_id, block_index),
+        Err(TopUpMinerError::WrongSender)
+    );
+    let top_up = top_up_miner(&pic, user_2, miner_id, block_index).unwrap();
+    assert_eq!(top_up.payer, user_2);
+    assert_eq!(top_up.amount_e8s, 20_000_000);
+    let cycles = match top_up.status {
+        TopUpStatus::ToppedUp { cycles } => cycles,
+        status => panic!("unexpected top-up status: {status:?}"),
+    };
+    assert!(cycles > 0);
+    assert!(pic.cycle_balance(miner_id) > cycles_before);
+
+    // the payment is used once
+    assert_eq!(
+        top_up_miner(&pic, user_2, miner_id, block_index),
+        Ok(top_up.clone())
+    );
+    assert_eq!(get_miner_top_ups(&pic, miner_id), vec![top_up]);
+
+    // the payment must cover more than the transfer fee
+    let block_index = transfer_to(&pic, user_2, to, 10_000);
+    assert_eq!(
+        top_up_miner(&pic, user_2, miner_id, block_index),
+        Err(TopUpMinerError::InsufficientAmount {
+            required: 10_001,
+            got: 10_000,
+        })
+    );
+
+    // unused payments to the minter are converted to cycles of the minter,
+    // which deposits them to the miner
+    let cycles_before = pic.cycle_balance(miner_id);
+    let block_index = transfer(&pic, user_1, 50_000_000);
+    let top_up = top_up_miner(&pic, user_1, miner_id, block_index).unwrap();
+    assert!(top_up.held_by_minter);
+    assert!(matches!(top_up.status, TopUpStatus::ToppedUp { .. }));
+    assert!(pic.cycle_balance(miner_id) > cycles_before);
+
+    // ICP sent elsewhere is not a top-up of the miner
+    let deposit_account = AccountIdentifier::new(&BOB_CANISTER_ID, &Subaccount::from(user_1));
+    let block_index = transfer_to(&pic, user_1, deposit_account, 100_000_000);
+    assert!(matches!(
+        top_up_miner(&pic, user_1, miner_id, block_index),
+        Err(TopUpMinerError::WrongDestination { .. })
+    ));
+}
+
+#[test]
+fn test_auto_top_up() {
+    let user_id = Principal::from_slice(&[0xFF; 29]);
+    let pic = setup(vec![user_id]);
+
+    let miner_id = spawn_miner(&pic, user_id, 100_000_000);
+    assert_eq!(deposit_icp(&pic, user_id, 150_000_000), 150_000_000);
+    let policy = AutoTopUpPolicy {
+        // always below the threshold
+        threshold_cycles: u128::MAX,
+        amount_e8s: 100_000_000,
+    };
+    assert_eq!(
+        set_auto_top_up(&pic, user_id, miner_id, Some(policy)),
+        Ok(())
+    );
+    let cycles_before = pic.cycle_balance(miner_id);
+
+    let wait_for_next_check = |pic: &pocket_ic::PocketIc| {
+        pic.advance_time(std::time::Duration::from_secs(60 * 60));
+        for _ in 0..20 {
+            pic.tick();
+        }
+    };
+
+    wait_for_next_check(&pic);
+    let top_ups = get_miner_top_ups(&pic, miner_id);
+    assert_eq!(top_ups.len(), 1);
+    assert!(matches!(top_ups[0].status, TopUpStatus::ToppedUp { .. }));
+    assert!(pic.cycle_balance(miner_id) > cycles_before);
+    assert_eq!(get_deposit(&pic, user_id), 49_990_000);
+
+    // the remaining deposit cannot pay another top-up
+    wait_for_next_check(&pic);
+    let auto_top_ups = get_auto_top_ups(&pic, user_id);
+    assert_eq!(auto_top_ups.len(), 1);
+    assert!(matches!(
+        auto_top_ups[0].status,
+        AutoTopUpStatus::Stopped { .. }
+    ));
+    assert_eq!(get_miner_top_ups(&pic, miner_id).len(), 1);
+    assert_eq!(get_deposit(&pic, user_id), 49_990_000);
+
+    // the owner takes the rest of the deposit back
+    assert!(withdraw_deposit(&pic, user_id, 50_000_000).is_err());
+    assert_eq!(withdraw_deposit(&pic, user_id, 40_000_000), Ok(9_990_000));
+    assert_eq!(get_deposit(&pic, user_id), 9_990_000);
+
+    // a decommissioned miner loses its policy
+    decommission_miner(&pic, user_id, miner_id, None).unwrap();
+    assert!(get_auto_top_ups(&pic, user_id).is_empty());
+}
+
+#[test]
+fn test_transfer_miner() {
+    let user_1 = Principal::from_slice(&[0xFF; 29]);
+    let user_2 = Principal::from_slice(&[0xFE; 29]);
+    let pic = setup(vec![user_1, user_2]);
+
+    let miner_id = spawn_miner(&pic, user_1, 100_000_000);
+    mine_block(&pic);
+    assert_eq!(bob_balance(&pic, user_1), 60_000_000_000_u64);
+
+    // the owner can no longer change the owner in the miner directly
+    assert!(update_candid_as::<_, ((),)>(
+        &pic,
+        miner_id,
+        user_1,
+        "update_miner_settings",
+        (MinerSettings {
+            max_cycles_per_round: None,
+            new_owner: Some(user_2),
+        },),
+    )
+    .is_err());
+
+    assert_eq!(
+        transfer_miner(&pic, user_2, miner_id, user_2),
+        Err(ManageMinerError::NotOwner)
+    );
+    assert_eq!(
+        transfer_miner(&pic, user_1, miner_id, Principal::anonymous()),
+        Err(ManageMinerError::AnonymousNewOwner)
+    );
+    transfer_miner(&pic, user_1, miner_id, user_2).unwrap();
+    assert_eq!(get_miner_state(&pic, miner_id).owner, user_2);
+
+    mine_block(&pic);
+    assert_eq!(bob_balance(&pic, user_1), 60_000_000_000_u64);
+    assert_eq!(bob_balance(&pic, user_2), 60_000_000_000_u64);
+
+    upgrade_miner(&pic, user_2, miner_id);
+    assert_eq!(get_miner_state(&pic, miner_id).owner, user_2);
+}
+
+#[test]
+fn test_upgrade_minter_keeps_state() {
+    let user_id = Principal::from_slice(&[0xFF; 29]);
+    let pic = setup(vec![user_id]);
+
+    spawn_miner(&pic, user_id, 100_000_000);
+    mine_block(&pic);
+    pic.advance_time(std::time::Duration::from_secs(120));
+
+    let stats_before_upgrade = get_stats(&pic);
+    assert!(stats_before_upgrade.time_since_last_block >= 120);
+    upgrade_bob(&pic);
+    let stats = get_stats(&pic);
+    assert_eq!(stats.block_count, stats_before_upgrade.block_count);
+    assert!(stats.time_since_last_block >= stats_before_upgrade.time_since_last_block);
+
+    mine_block(&pic);
+    assert_eq!(bob_balance(&pic, user_id), 120_000_000_000_u64);
+}
+
+#[test]
+fn test_event_log() {
+    let user_id = Principal::from_slice(&[0xFF; 29]);
+    let pic = setup(vec![user_id]);
+
+    let miner_id = spawn_miner(&pic, user_id, 100_000_000);
+    mine_block(&pic);
+
+    let result = get_events(&pic, 0, 1_000);
+    assert_eq!(result.total_event_count, result.events.len() as u64);
+    let events: Vec<EventType> = result.events.into_iter().map(|e| e.payload).collect();
+    assert!(matches!(events[0], EventType::Init { .. }));
+    assert!(events.iter().any(|e| matches!(
+        e,
+        EventType::MinerSpawned { miner, owner, .. } if *miner == miner_id && *owner == user_id
+    )));
+    assert!(events.iter().any(|e| matches!(
+        e,
+        EventType::ChallengeSolved { miner, .. } if *miner == miner_id
+    )));
+    assert!(events.iter().any(|e| matches!(
+        e,
+        EventType::RewardPaid { recipient, amount, .. }
+            if *recipient == user_id && *amount == 60_000_000_000
+    )));
+
+    upgrade_bob(&pic);
+    let result = get_events(&pic, 0, 1_000);
+    assert!(matches!(
+        result.events.last().map(|e| &e.payload),
+        Some(EventType::Upgrade { .. })
+    ));
+}
+
+#[test]
+fn test_spawn_miner_with_invalid_payment() {
+    let user_1 = Principal::from_slice(&[0xFF; 29]);
+    let user_2 = Principal::from_slice(&[0xFE; 29]);
+    let pic = setup(vec![user_1, user_2]);
+
+    let block_index = transfer(&pic, user_1, 50_000_000);
+    assert_eq!(
+        spawn_miner_from_block(&pic, user_1, block_index),
+        Err(SpawnMinerError::InsufficientAmount {
+            required: 99_990_000,
+            got: 50_000_000
+        })
+    );
+
+    let block_index = transfer(&pic, user_1, 100_000_000);
+    assert_eq!(
+        spawn_miner_from_block(&pic, user_2, block_index),
+        Err(SpawnMinerError::WrongSender)
+    );
+    assert!(spawn_miner_from_block(&pic, user_1, block_index).is_ok());
+    assert_eq!(
+        spawn_miner_from_block(&pic, user_1, block_index),
+        Err(SpawnMinerError::AlreadyConsumed)
+    );
+
+    assert_eq!(
+        spawn_miner_from_block(&pic, user_1, block_index + 1_000),
+        Err(SpawnMinerError::NotYetIndexed {
+            block_index: block_index + 1_000
+        })
+    );
+}
+
+#[test]
+fn test_resume_spawn() {
+    let user_1 = Principal::from_slice(&[0xFF; 29]);
+    let user_2 = Principal::from_slice(&[0xFE; 29]);
+    let pic = setup(vec![user_1, user_2]);
+
+    let block_index = transfer(&pic, user_1, 100_000_000);
+    let miner = spawn_miner_from_block(&pic, user_1, block_index).unwrap();
+
+    let request = get_spawn_request(&pic, block_index).unwrap();
+    assert_eq!(request.owner, user_1);
+    assert_eq!(request.step, SpawnStep::Installed { miner });
+
+    // resuming a finished spawn returns its miner
+    assert_eq!(resume_spawn(&pic, user_1, block_index), Ok(miner));
+    assert_eq!(
+        resume_spawn(&pic, user_2, block_index),
+        Err(SpawnMinerError::WrongSender)
+    );
+    assert_eq!(
+        resume_spawn(&pic, user_1, block_index + 1),
+        Err(SpawnMinerError::UnknownSpawnRequest)
+    );
+    assert_eq!(get_spawn_request(&pic, block_index + 1), None);
+}
+
+#[test]
+fn test_request_refund() {
+    let user_1 = Principal::from_slice(&[0xFF; 29]);
+    let user_2 = Principal::from_slice(&[0xFE; 29]);
+    let pic = setup(vec![user_1, user_2]);
+
+    // ICP sent to the deposit account without `deposit_icp` is refunded
+    // from that account
+    let deposit_account = AccountIdentifier::new(&BOB_CANISTER_ID, &Subaccount::from(user_1));
+    let block_index = transfer_to(&pic, user_1, deposit_account, 50_000_000);
+
+    assert_eq!(
+        request_refund(&pic, user_2, block_index),
+        Err(RefundError::WrongSender)
+    );
+    let refund = request_refund(&pic, user_1, block_index).unwrap();
+    assert_eq!(refund.recipient, user_1);
+    assert_eq!(refund.amount_e8s, 49_990_000);
+    assert!(matches!(refund.status, RefundStatus::Refunded { .. }));
+
+    // refunding again returns the same refund
+    assert_eq!(
+        request_refund(&pic, user_1, block_index),
+        Ok(refund.clone())
+    );
+    assert_eq!(get_refunds(&pic, user_1), vec![refund]);
+    assert_eq!(get_deposit(&pic, user_1), 0);
+
+    // payments to the cycles minting canister stay there
+    let held_block_index = transfer(&pic, user_1, 50_000_000);
+    assert!(spawn_miner_from_block(&pic, user_1, held_block_index).is_err());
+    assert_eq!(
+        request_refund(&pic, user_1, held_block_index),
+        Err(RefundError::HeldByCyclesMinter)
+    );
+
+    let block_index = transfer(&pic, user_1, 100_000_000);
+    let miner_id = spawn_miner_from_block(&pic, user_1, block_index).unwrap();
+    assert_eq!(
+        request_refund(&pic, user_1, block_index),
+        Err(RefundError::AlreadyConsumed)
+    );
+
+    // but their cycles can go to a miner of the payer
+    let top_up = top_up_miner(&pic, user_1, miner_id, held_block_index).unwrap();
+    assert!(matches!(top_up.status, TopUpStatus::ToppedUp { .. }));
+    assert_eq!(
+        request_refund(&pic, user_1, held_block_index),
+        Err(RefundError::AlreadyConsumed)
+    );
+}
+
 #[test]
 fn test_native_pool() {
     let user_1 = Principal::from_slice(&[0xFF; 29]);
@@ -67,6 +480,133 @@ fn test_native_pool() {
     assert_eq!(bob_balance(&pic, user_1), 0_u64);
     assert_eq!(bob_balance(&pic, user_2), 0_u64);
     mine_block(&pic);
-    assert_eq!(bob_balance(&pic, user_1), 30_000_000_000_u64);
-    assert_eq!(bob_balance(&pic, user_2), 30_000_000_000_u64);
+    // rewards are split in proportion to the ICP paid for the membership
+    assert_eq!(bob_balance(&pic, user_1), 20_000_000_000_u64);
+    assert_eq!(bob_balance(&pic, user_2), 40_000_000_000_u64);
+}
+
+#[test]
+fn test_owner_overview() {
+    let user_id = Principal::from_slice(&[0xFF; 29]);
+    let pic = setup(vec![user_id]);
+
+    let block_index = transfer(&pic, user_id, 100_000_000);
+    let miner_id = spawn_miner_from_block(&pic, user_id, block_index).unwrap();
+    mine_block(&pic);
+    join_native_pool(&pic, user_id, 200_000_000);
+
+    let overview = get_owner_overview(&pic, user_id);
+    assert_eq!(overview.miners.len(), 1);
+    let miner = &overview.miners[0];
+    assert_eq!(miner.id, miner_id);
+    assert_eq!(miner.mined_blocks, 1);
+    assert_eq!(miner.spawn_block_index, Some(block_index));
+    assert!(miner.lifetime_cycles > 0);
+    assert!(miner.last_submission_at.is_some());
+
+    assert!(overview.pool_expiration.is_some());
+    assert_eq!(overview.pool_stake_e8s, 200_000_000);
+    assert_eq!(overview.pool_paid_e8s, 200_000_000);
+    assert_eq!(overview.bob_earned_solo, 60_000_000_000);
+    assert_eq!(overview.bob_earned_pool, 0);
+    assert!(overview.pending_payouts.is_empty());
+}
+
+#[test]
+fn test_spawn_miner_and_join_pool_with_approval() {
+    let user_1 = Principal::from_slice(&[0xFF; 29]);
+    let user_2 = Principal::from_slice(&[0xFE; 29]);
+    let pic = setup(vec![user_1, user_2]);
+
+    spawn_miner_with_approval(&pic, user_1);
+    mine_block(&pic);
+    assert_eq!(bob_balance(&pic, user_1), 60_000_000_000_u64);
+
+    join_pool_with_approval(&pic, user_2, 2);
+    let events = get_events(&pic, 0, 1_000).events;
+    assert!(events.iter().any(|e| matches!(
+        e.payload,
+        EventType::PoolJoined { owner, amount_e8s, .. }
+            if owner == user_2 && amount_e8s == 200_000_000
+    )));
+}
+
+#[test]
+fn test_icrc3_block_log() {
+    let user_id = Principal::from_slice(&[0xFF; 29]);
+    let pic = setup(vec![user_id]);
+
+    spawn_miner(&pic, user_id, 100_000_000);
+    mine_block(&pic);
+    mine_block(&pic);
+
+    let result = icrc3_get_blocks(&pic, 0, 10);
+    assert_eq!(result.log_length, Nat::from(2_u8));
+    assert_eq!(result.blocks.len(), 2);
+    assert!(result.archived_blocks.is_empty());
+
+    let parent_hash = result.blocks[0].block.clone().hash();
+    match &result.blocks[1].block {
+        ICRC3Value::Map(block) => match block.get("phash") {
+            Some(ICRC3Value::Blob(phash)) => assert_eq!(phash.to_vec(), parent_hash.to_vec()),
+            phash => panic!("unexpected parent hash: {phash:?}"),
+        },
+        block => panic!("unexpected block: {block:?}"),
+    }
+}
+
+#[test]
+fn test_get_blocks_with_filter() {
+    let user_1 = Principal::from_slice(&[0xFF; 29]);
+    let user_2 = Principal::from_slice(&[0xFE; 29]);
+    let pic = setup(vec![user_1, user_2]);
+
+    let miner_id = spawn_miner(&pic, user_1, 100_000_000);
+    mine_block(&pic);
+    mine_block(&pic);
+
+    let page = get_blocks(&pic, 0, 1, None);
+    assert_eq!(page.total_blocks, 2);
+    assert_eq!(page.blocks.len(), 1);
+    assert_eq!(page.blocks[0].index, 0);
+    assert_eq!(page.next_start, Some(1));
+
+    let page = get_blocks(&pic, 1, 10, None);
+    assert_eq!(page.blocks.len(), 1);
+    assert_eq!(page.blocks[0].index, 1);
+    assert_eq!(page.next_start, None);
+
+    let by_miner = get_blocks(
+        &pic,
+        0,
+        10,
+        Some(BlockFilter {
+            miner: Some(miner_id),
+            ..Default::default()
+        }),
+    );
+    assert_eq!(by_miner.blocks.len(), 2);
+
+    let by_other_owner = get_blocks(
+        &pic,
+        0,
+        10,
+        Some(BlockFilter {
+            owner: Some(user_2),
+            ..Default::default()
+        }),
+    );
+    assert!(by_other_owner.blocks.is_empty());
+    assert_eq!(by_other_owner.next_start, None);
+
+    let after_last_block = get_blocks(
+        &pic,
+        0,
+        10,
+        Some(BlockFilter {
+            from_timestamp: Some(page.blocks[0].block.timestamp + 1),
+            ..Default::default()
+        }),
+    );
+    assert!(after_last_block.blocks.is_empty());
 }
@@ -194,6 +194,17 @@ fn deploy_bob(pic: &PocketIc) {
     );
 }
 
+pub(crate) fn upgrade_bob(pic: &PocketIc) {
+    let bob_canisterwasm = get_canister_wasm("bob-minter-v2").to_vec();
+    pic.upgrade_canister(
+        BOB_CANISTER_ID,
+        bob_canisterwasm,
+        Encode!(&()).unwrap(),
+        Some(NNS_ROOT_CANISTER_ID),
+    )
+    .unwrap();
+}
+
 fn deploy_bob_ledger(pic: &PocketIc) {
     let bob_ledger_canister_id = pic
         .create_canister_with_id(Some(NNS_ROOT_CANISTER_ID), None, BOB_LEDGER_CANISTER_ID)
@@ -1,11 +1,22 @@
 use crate::{
     BOB_CANISTER_ID, BOB_LEDGER_CANISTER_ID, NNS_ICP_INDEX_CANISTER_ID, NNS_ICP_LEDGER_CANISTER_ID,
 };
-use bob_minter_v2::Stats;
-use candid::{Nat, Principal};
+use bob_minter_v2::auto_top_up::{AutoTopUp, AutoTopUpPolicy};
+use bob_minter_v2::event::{GetEventsArg, GetEventsResult};
+use bob_minter_v2::health::MinerHealth;
+use bob_minter_v2::miner::ManageMinerError;
+use bob_minter_v2::overview::OwnerOverview;
+use bob_minter_v2::payment::{JoinPoolError, SpawnMinerError};
+use bob_minter_v2::refund::{Refund, RefundError};
+use bob_minter_v2::spawn::SpawnRequest;
+use bob_minter_v2::top_up::{MinerTopUp, TopUpMinerError};
+use bob_minter_v2::{BlockFilter, GetBlocksResponse, Stats};
+use candid::{CandidType, Nat, Principal};
 use ic_ledger_core::block::BlockType;
 use ic_ledger_types::{AccountIdentifier, Memo, Tokens, TransferArgs, TransferResult};
 use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc2::approve::{ApproveArgs, ApproveError};
+use icrc_ledger_types::icrc3::blocks::{GetBlocksRequest, GetBlocksResult};
 use pocket_ic::{update_candid_as, PocketIc};
 
 pub(crate) fn get_icp_block(pic: &PocketIc, block_index: u64) -> Option<icp_ledger::Block> {
@@ -29,15 +40,26 @@ pub(crate) fn get_icp_block(pic: &PocketIc, block_index: u64) -> Option<icp_ledg
 }
 
 pub(crate) fn transfer(pic: &PocketIc, user_id: Principal, amount: u64) -> u64 {
+    let to = AccountIdentifier::from_hex(
+        "e7b583c3e3e2837c987831a97a6b980cbb0be89819e85915beb3c02006923fce",
+    )
+    .unwrap();
+    transfer_to(pic, user_id, to, amount)
+}
+
+/// Transfers ICP with the top-up memo.
+pub(crate) fn transfer_to(
+    pic: &PocketIc,
+    user_id: Principal,
+    to: AccountIdentifier,
+    amount: u64,
+) -> u64 {
     let transfer_args = TransferArgs {
         memo: Memo(1347768404),
         amount: Tokens::from_e8s(amount),
         from_subaccount: None,
         fee: Tokens::from_e8s(10_000),
-        to: AccountIdentifier::from_hex(
-            "e7b583c3e3e2837c987831a97a6b980cbb0be89819e85915beb3c02006923fce",
-        )
-        .unwrap(),
+        to,
         created_at_time: None,
     };
     let block_index = update_candid_as::<_, (TransferResult,)>(
@@ -62,8 +84,15 @@ pub(crate) fn transfer(pic: &PocketIc, user_id: Principal, amount: u64) -> u64 {
 
 pub(crate) fn spawn_miner(pic: &PocketIc, user_id: Principal, amount: u64) -> Principal {
     let block_index = transfer(pic, user_id, amount);
+    spawn_miner_from_block(pic, user_id, block_index).unwrap()
+}
 
-    update_candid_as::<_, (Result<Principal, String>,)>(
+pub(crate) fn spawn_miner_from_block(
+    pic: &PocketIc,
+    user_id: Principal,
+    block_index: u64,
+) -> Result<Principal, SpawnMinerError> {
+    update_candid_as::<_, (Result<Principal, SpawnMinerError>,)>(
         pic,
         BOB_CANISTER_ID,
         user_id,
@@ -72,7 +101,189 @@ pub(crate) fn spawn_miner(pic: &PocketIc, user_id: Principal, amount: u64) -> Pr
     )
     .unwrap()
     .0
+}
+
+pub(crate) fn resume_spawn(
+    pic: &PocketIc,
+    user_id: Principal,
+    block_index: u64,
+) -> Result<Principal, SpawnMinerError> {
+    update_candid_as::<_, (Result<Principal, SpawnMinerError>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "resume_spawn",
+        (block_index,),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn get_spawn_request(pic: &PocketIc, block_index: u64) -> Option<SpawnRequest> {
+    update_candid_as::<_, (Option<SpawnRequest>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "get_spawn_request",
+        (block_index,),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn request_refund(
+    pic: &PocketIc,
+    user_id: Principal,
+    block_index: u64,
+) -> Result<Refund, RefundError> {
+    update_candid_as::<_, (Result<Refund, RefundError>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "request_refund",
+        (block_index,),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn get_refunds(pic: &PocketIc, user_id: Principal) -> Vec<Refund> {
+    update_candid_as::<_, (Vec<Refund>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "get_refunds",
+        (Some(user_id),),
+    )
     .unwrap()
+    .0
+}
+
+pub(crate) fn approve_bob(pic: &PocketIc, user_id: Principal, amount: u64) {
+    update_candid_as::<_, (Result<Nat, ApproveError>,)>(
+        pic,
+        NNS_ICP_LEDGER_CANISTER_ID,
+        user_id,
+        "icrc2_approve",
+        (ApproveArgs {
+            from_subaccount: None,
+            spender: Account {
+                owner: BOB_CANISTER_ID,
+                subaccount: None,
+            },
+            amount: Nat::from(amount),
+            expected_allowance: None,
+            expires_at: None,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        },),
+    )
+    .unwrap()
+    .0
+    .unwrap();
+}
+
+pub(crate) fn spawn_miner_with_approval(pic: &PocketIc, user_id: Principal) -> Principal {
+    approve_bob(pic, user_id, 100_010_000);
+
+    update_candid_as::<_, (Result<Principal, SpawnMinerError>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "spawn_miner_with_approval",
+        ((),),
+    )
+    .unwrap()
+    .0
+    .unwrap()
+}
+
+pub(crate) fn join_pool_with_approval(pic: &PocketIc, user_id: Principal, days: u64) {
+    approve_bob(pic, user_id, days * 100_000_000 + 10_000);
+
+    update_candid_as::<_, (Result<(), JoinPoolError>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "join_pool_with_approval",
+        (days,),
+    )
+    .unwrap()
+    .0
+    .unwrap()
+}
+
+pub(crate) fn deposit_icp(pic: &PocketIc, user_id: Principal, amount_e8s: u64) -> u64 {
+    approve_bob(pic, user_id, amount_e8s + 10_000);
+
+    update_candid_as::<_, (Result<u64, String>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "deposit_icp",
+        (amount_e8s,),
+    )
+    .unwrap()
+    .0
+    .unwrap()
+}
+
+pub(crate) fn withdraw_deposit(
+    pic: &PocketIc,
+    user_id: Principal,
+    amount_e8s: u64,
+) -> Result<u64, String> {
+    update_candid_as::<_, (Result<u64, String>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "withdraw_deposit",
+        (amount_e8s,),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn get_deposit(pic: &PocketIc, user_id: Principal) -> u64 {
+    update_candid_as::<_, (u64,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "get_deposit",
+        (Some(user_id),),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn set_auto_top_up(
+    pic: &PocketIc,
+    user_id: Principal,
+    miner_id: Principal,
+    policy: Option<AutoTopUpPolicy>,
+) -> Result<(), String> {
+    update_candid_as::<_, (Result<(), String>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "set_auto_top_up",
+        (miner_id, policy),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn get_auto_top_ups(pic: &PocketIc, user_id: Principal) -> Vec<AutoTopUp> {
+    update_candid_as::<_, (Vec<AutoTopUp>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "get_auto_top_ups",
+        (Some(user_id),),
+    )
+    .unwrap()
+    .0
 }
 
 pub(crate) fn upgrade_miner(pic: &PocketIc, user_id: Principal, miner_id: Principal) {
@@ -88,10 +299,98 @@ pub(crate) fn upgrade_miner(pic: &PocketIc, user_id: Principal, miner_id: Princi
     .unwrap()
 }
 
+pub(crate) fn pause_miner(pic: &PocketIc, user_id: Principal, miner_id: Principal) {
+    update_candid_as::<_, (Result<(), ManageMinerError>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "pause_miner",
+        (miner_id,),
+    )
+    .unwrap()
+    .0
+    .unwrap()
+}
+
+pub(crate) fn resume_miner(pic: &PocketIc, user_id: Principal, miner_id: Principal) {
+    update_candid_as::<_, (Result<(), ManageMinerError>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "resume_miner",
+        (miner_id,),
+    )
+    .unwrap()
+    .0
+    .unwrap()
+}
+
+pub(crate) fn decommission_miner(
+    pic: &PocketIc,
+    user_id: Principal,
+    miner_id: Principal,
+    cycles_to: Option<Principal>,
+) -> Result<u128, ManageMinerError> {
+    update_candid_as::<_, (Result<u128, ManageMinerError>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "decommission_miner",
+        (miner_id, cycles_to),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn transfer_miner(
+    pic: &PocketIc,
+    user_id: Principal,
+    miner_id: Principal,
+    new_owner: Principal,
+) -> Result<(), ManageMinerError> {
+    update_candid_as::<_, (Result<(), ManageMinerError>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "transfer_miner",
+        (miner_id, new_owner),
+    )
+    .unwrap()
+    .0
+}
+
+#[derive(CandidType)]
+pub(crate) struct MinerSettings {
+    pub max_cycles_per_round: Option<u128>,
+    pub new_owner: Option<Principal>,
+}
+
+pub(crate) fn update_miner_settings(
+    pic: &PocketIc,
+    user_id: Principal,
+    miner_id: Principal,
+    settings: MinerSettings,
+) {
+    update_candid_as::<_, ((),)>(pic, miner_id, user_id, "update_miner_settings", (settings,))
+        .unwrap();
+}
+
+pub(crate) fn get_miner_state(pic: &PocketIc, miner_id: Principal) -> bob_miner_v2::State {
+    update_candid_as::<_, (bob_miner_v2::State,)>(
+        pic,
+        miner_id,
+        Principal::anonymous(),
+        "get_state",
+        ((),),
+    )
+    .unwrap()
+    .0
+}
+
 pub(crate) fn join_native_pool(pic: &PocketIc, user_id: Principal, amount: u64) {
     let block_index = transfer(pic, user_id, amount);
 
-    update_candid_as::<_, (Result<(), String>,)>(
+    update_candid_as::<_, (Result<(), JoinPoolError>,)>(
         pic,
         BOB_CANISTER_ID,
         user_id,
@@ -103,6 +402,71 @@ pub(crate) fn join_native_pool(pic: &PocketIc, user_id: Principal, amount: u64)
     .unwrap()
 }
 
+pub(crate) fn get_miner_health(pic: &PocketIc, miner_id: Principal) -> Option<MinerHealth> {
+    update_candid_as::<_, (Option<MinerHealth>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "get_miner_health",
+        (miner_id,),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn get_low_cycles_miners(pic: &PocketIc, threshold: Option<u128>) -> Vec<MinerHealth> {
+    update_candid_as::<_, (Vec<MinerHealth>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "get_low_cycles_miners",
+        (threshold,),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn top_up_miner(
+    pic: &PocketIc,
+    user_id: Principal,
+    miner_id: Principal,
+    block_index: u64,
+) -> Result<MinerTopUp, TopUpMinerError> {
+    update_candid_as::<_, (Result<MinerTopUp, TopUpMinerError>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        user_id,
+        "top_up_miner",
+        (miner_id, block_index),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn get_miner_top_ups(pic: &PocketIc, miner_id: Principal) -> Vec<MinerTopUp> {
+    update_candid_as::<_, (Vec<MinerTopUp>,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "get_miner_top_ups",
+        (miner_id,),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn get_owner_overview(pic: &PocketIc, owner: Principal) -> OwnerOverview {
+    update_candid_as::<_, (OwnerOverview,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "get_owner_overview",
+        (owner,),
+    )
+    .unwrap()
+    .0
+}
+
 pub(crate) fn get_stats(pic: &PocketIc) -> Stats {
     update_candid_as::<_, (Stats,)>(
         pic,
@@ -124,7 +488,11 @@ pub(crate) fn mine_block(pic: &PocketIc) {
         let new_stats = get_stats(pic);
         if new_stats.block_count > old_stats.block_count {
             assert_eq!(new_stats.block_count, old_stats.block_count + 1);
-            while !get_stats(pic).pending_blocks.is_empty() {
+            loop {
+                let stats = get_stats(pic);
+                if stats.pending_blocks.is_empty() && stats.outstanding_payouts == 0 {
+                    break;
+                }
                 pic.tick();
             }
             break;
@@ -149,3 +517,47 @@ pub(crate) fn bob_balance(pic: &PocketIc, user_id: Principal) -> u64 {
     .try_into()
     .unwrap()
 }
+
+pub(crate) fn icrc3_get_blocks(pic: &PocketIc, start: u64, length: u64) -> GetBlocksResult {
+    update_candid_as::<_, (GetBlocksResult,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "icrc3_get_blocks",
+        (vec![GetBlocksRequest {
+            start: start.into(),
+            length: length.into(),
+        }],),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn get_blocks(
+    pic: &PocketIc,
+    start: u64,
+    length: u64,
+    filter: Option<BlockFilter>,
+) -> GetBlocksResponse {
+    update_candid_as::<_, (GetBlocksResponse,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "get_blocks",
+        (start, length, filter),
+    )
+    .unwrap()
+    .0
+}
+
+pub(crate) fn get_events(pic: &PocketIc, start: u64, length: u64) -> GetEventsResult {
+    update_candid_as::<_, (GetEventsResult,)>(
+        pic,
+        BOB_CANISTER_ID,
+        Principal::anonymous(),
+        "get_events",
+        (GetEventsArg { start, length },),
+    )
+    .unwrap()
+    .0
+}
@@ -1,4 +1,9 @@
 use candid::{CandidType, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableCell, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::cell::RefCell;
 
 const DEFAULT_BURNED_CYCLES_PER_ROUND: u128 = 10_000_000_001;
@@ -41,11 +46,28 @@ async fn submit_burned_cycles(cycles: u64) -> Result<(), String> {
     }
 }
 
+// NOTE: ensure that all memory ids are unique and
+// do not change across upgrades!
+const STATE_MEM_ID: MemoryId = MemoryId::new(0);
+
+type VM = VirtualMemory<DefaultMemoryImpl>;
+
 thread_local! {
     static __STATE: RefCell<Option<State>> = RefCell::default();
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    /// Copy of the state written on every change, restored in `post_upgrade`.
+    static STABLE_STATE: RefCell<StableCell<StableState, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(mm.borrow().get(STATE_MEM_ID), StableState::default())
+            .expect("failed to initialize the stable state"))
+        });
 }
 
-#[derive(Clone, CandidType)]
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct State {
     pub bob_minter_id: Principal,
     pub owner: Principal,
@@ -69,11 +91,35 @@ impl State {
     }
 }
 
+/// CBOR encoded state, empty until the state is first saved.
+#[derive(Clone, Default)]
+struct StableState(Option<State>);
+
+impl Storable for StableState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        ciborium::ser::into_writer(&self.0, &mut buf).expect("failed to encode the state");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(ciborium::de::from_reader(bytes.as_ref()).expect("failed to decode the state"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
 pub fn mutate_state<F, R>(f: F) -> R
 where
     F: FnOnce(&mut State) -> R,
 {
-    __STATE.with(|s| f(s.borrow_mut().as_mut().expect("State not initialized!")))
+    __STATE.with(|s| {
+        let mut state = s.borrow_mut();
+        let state = state.as_mut().expect("State not initialized!");
+        let result = f(state);
+        save_state(state.clone());
+        result
+    })
 }
 
 pub fn read_state<F, R>(f: F) -> R
@@ -84,7 +130,19 @@ where
 }
 
 pub fn replace_state(state: State) {
+    save_state(state.clone());
     __STATE.with(|s| {
         *s.borrow_mut() = Some(state);
     });
 }
+
+fn save_state(state: State) {
+    STABLE_STATE
+        .with(|s| s.borrow_mut().set(StableState(Some(state))))
+        .expect("failed to save the state");
+}
+
+/// Returns the state saved in stable memory, if any.
+pub fn load_state() -> Option<State> {
+    STABLE_STATE.with(|s| s.borrow().get().0.clone())
+}
@@ -1,6 +1,7 @@
-use bob_miner_v2::{mutate_state, process_logic, read_state, replace_state, State};
+use bob_miner_v2::{load_state, mutate_state, process_logic, read_state, replace_state, State};
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::{init, query, update};
+use ic_cdk::api::management_canister::main::{deposit_cycles, CanisterIdRecord};
+use ic_cdk::{init, post_upgrade, query, update};
 use std::time::Duration;
 
 fn main() {}
@@ -12,6 +13,15 @@ fn init(owner: Principal) {
     replace_state(State::from_init(owner));
 }
 
+/// Restores the saved state. Miners installed before the state was kept
+/// in stable memory start over from the init state of the given owner.
+#[post_upgrade]
+fn post_upgrade(owner: Principal) {
+    setup_timer();
+
+    replace_state(load_state().unwrap_or_else(|| State::from_init(owner)));
+}
+
 const ROUND_LENGTH_SECS: u64 = 240;
 
 fn setup_timer() {
@@ -34,11 +44,19 @@ struct MinerSettings {
     new_owner: Option<Principal>,
 }
 
+/// The owner can change the settings of the miner. Only the minter can
+/// change the owner, through its `transfer_miner` endpoint, so that the
+/// owner it pays the rewards to stays the same.
 #[update]
 fn update_miner_settings(settings: MinerSettings) {
-    if ic_cdk::caller() != read_state(|s| s.owner) {
+    let caller = ic_cdk::caller();
+    let (owner, bob_minter_id) = read_state(|s| (s.owner, s.bob_minter_id));
+    if caller != owner && caller != bob_minter_id {
         ic_cdk::trap("caller not owner");
     }
+    if settings.new_owner.is_some() && caller != bob_minter_id {
+        ic_cdk::trap("only the minter can change the owner, use transfer_miner");
+    }
     mutate_state(|s| {
         if let Some(hash_limit_per_round) = settings.max_cycles_per_round {
             s.max_cycles_per_round = hash_limit_per_round;
@@ -50,6 +68,23 @@ fn update_miner_settings(settings: MinerSettings) {
     })
 }
 
+/// Cycles the miner keeps when withdrawing, to pay for the deposit.
+const WITHDRAW_RESERVE_CYCLES: u128 = 100_000_000_000;
+
+/// Deposits the cycles of the miner, except for a small reserve, to the
+/// canister `to`. Called by the minter before it deletes the miner.
+#[update]
+async fn withdraw_cycles(to: Principal) -> Result<u128, String> {
+    if ic_cdk::caller() != read_state(|s| s.bob_minter_id) {
+        ic_cdk::trap("caller not minter");
+    }
+    let cycles = ic_cdk::api::canister_balance128().saturating_sub(WITHDRAW_RESERVE_CYCLES);
+    deposit_cycles(CanisterIdRecord { canister_id: to }, cycles)
+        .await
+        .map_err(|(code, msg)| format!("Error while depositing cycles ({:?}): {}", code, msg))?;
+    Ok(cycles)
+}
+
 #[derive(CandidType)]
 struct StatsV2 {
     cycle_balance: u64,
@@ -0,0 +1,519 @@
+use crate::config::TOP_UP_MEMO;
+use crate::event::{process_event, EventType};
+use crate::guard::GuardPrincipal;
+use crate::memory::{
+    get_auto_top_up, get_auto_top_ups, get_deposit, get_miner_health, get_miner_owner,
+    get_pending_withdrawal, get_top_up, insert_auto_top_up, insert_block_index,
+    insert_pending_withdrawal, insert_top_up, remove_auto_top_up, remove_pending_withdrawal,
+    set_deposit,
+};
+use crate::refund::ICP_TRANSFER_FEE_E8S;
+use crate::top_up::{MinerTopUp, TopUpStatus};
+use crate::{
+    notify_top_up, transfer_from_subaccount, SubaccountTransferError,
+    MAINNET_CYCLE_MINTER_CANISTER_ID, MAINNET_LEDGER_CANISTER_ID,
+};
+use candid::{CandidType, Nat, Principal};
+use cycles_minting_canister::NotifyError;
+use ic_types::PrincipalId;
+use icp_ledger::Subaccount;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub struct AutoTopUpPolicy {
+    /// The miner is topped up once the minter sees fewer cycles.
+    pub threshold_cycles: u128,
+    /// ICP converted per top-up, the transfer fee is paid on top.
+    pub amount_e8s: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub enum AutoTopUpStatus {
+    Active,
+    /// No more top-ups until the owner sets the policy again.
+    Stopped {
+        reason: String,
+    },
+}
+
+/// A transfer from a deposit whose outcome is not known yet. It is retried
+/// with the same arguments, so that the ledger deduplicates it.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct PendingTransfer {
+    /// What the transfer takes from the deposit, the fee included.
+    pub amount_e8s: u64,
+    pub created_at_time: u64,
+}
+
+/// A miner topped up from the ICP deposit of its owner.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct AutoTopUp {
+    pub miner: Principal,
+    pub owner: Principal,
+    pub threshold_cycles: u128,
+    pub amount_e8s: u64,
+    pub status: AutoTopUpStatus,
+    pub last_top_up_at: u64,
+    /// ICP block of a transfer to the cycles minting canister that was not
+    /// converted yet, retried before any new top-up.
+    pub pending_block_index: Option<u64>,
+    /// Transfer to the cycles minting canister that may not have happened,
+    /// its cost stays deducted from the deposit until the ledger answers.
+    #[serde(default)]
+    pub pending_transfer: Option<PendingTransfer>,
+    pub last_error: Option<String>,
+}
+
+impl AutoTopUp {
+    pub fn new(miner: Principal, owner: Principal, policy: AutoTopUpPolicy) -> Self {
+        Self {
+            miner,
+            owner,
+            threshold_cycles: policy.threshold_cycles,
+            amount_e8s: policy.amount_e8s,
+            status: AutoTopUpStatus::Active,
+            last_top_up_at: 0,
+            pending_block_index: None,
+            pending_transfer: None,
+            last_error: None,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.status == AutoTopUpStatus::Active
+    }
+
+    /// Whether a transfer or its conversion to cycles is not finished.
+    pub fn has_pending(&self) -> bool {
+        self.pending_block_index.is_some() || self.pending_transfer.is_some()
+    }
+
+    /// What a top-up takes from the deposit.
+    fn cost_e8s(&self) -> u64 {
+        self.amount_e8s.saturating_add(ICP_TRANSFER_FEE_E8S)
+    }
+
+    fn stop(&mut self, reason: String) {
+        process_event(EventType::AutoTopUpStopped {
+            miner: self.miner,
+            owner: self.owner,
+            reason: reason.clone(),
+        });
+        self.status = AutoTopUpStatus::Stopped { reason };
+    }
+}
+
+/// The subaccount of the minter holding the ICP deposit of the owner.
+pub fn deposit_subaccount(owner: Principal) -> Subaccount {
+    Subaccount::from(&PrincipalId(owner))
+}
+
+/// Pulls ICP approved by `owner` (ICRC-2) into its deposit. Returns the
+/// new balance of the deposit. The caller must hold the guard of the owner.
+pub async fn deposit_icp(owner: Principal, amount_e8s: u64) -> Result<u64, String> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account::from(owner),
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(deposit_subaccount(owner).0),
+        },
+        amount: Nat::from(amount_e8s),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(MAINNET_LEDGER_CANISTER_ID, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, msg)| {
+                format!("Error while calling the ICP ledger ({:?}): {}", code, msg)
+            })?;
+    let block_index: u64 = result.map_err(|e| format!("{e:?}"))?.0.try_into().unwrap();
+
+    // The deposited ICP is not a refundable transfer to the deposit account.
+    insert_block_index(block_index);
+    let balance = get_deposit(owner).saturating_add(amount_e8s);
+    set_deposit(owner, balance);
+    process_event(EventType::IcpDeposited {
+        owner,
+        amount_e8s,
+        block_index,
+    });
+    Ok(balance)
+}
+
+/// Sets the auto top-up policy of the miner of `owner`, or disables auto
+/// top-ups without a policy. A pending transfer is kept either way. The
+/// caller must hold the guard of the owner.
+pub fn set_auto_top_up(
+    miner: Principal,
+    owner: Principal,
+    policy: Option<AutoTopUpPolicy>,
+) -> Result<(), String> {
+    let policy = match policy {
+        Some(policy) => policy,
+        None => {
+            disable_auto_top_up(miner, "disabled by the owner");
+            return Ok(());
+        }
+    };
+    if policy.amount_e8s <= ICP_TRANSFER_FEE_E8S {
+        return Err(format!(
+            "the top-up amount must exceed the transfer fee of {ICP_TRANSFER_FEE_E8S} e8s"
+        ));
+    }
+    let current = get_auto_top_up(miner);
+    insert_auto_top_up(AutoTopUp {
+        last_top_up_at: current.as_ref().map(|a| a.last_top_up_at).unwrap_or(0),
+        pending_block_index: current.as_ref().and_then(|a| a.pending_block_index),
+        pending_transfer: current.and_then(|a| a.pending_transfer),
+        ..AutoTopUp::new(miner, owner, policy)
+    });
+    Ok(())
+}
+
+/// Removes the auto top-up policy of the miner. A top-up with a pending
+/// transfer is stopped instead, the next run still finishes the transfer.
+pub fn disable_auto_top_up(miner: Principal, reason: &str) {
+    match get_auto_top_up(miner) {
+        Some(mut current) if current.has_pending() => {
+            current.stop(reason.to_string());
+            insert_auto_top_up(current);
+        }
+        Some(_) => remove_auto_top_up(miner),
+        None => {}
+    }
+}
+
+/// Sends `amount_e8s` of the deposit of `owner`, minus the transfer fee, to
+/// its ICP account. Returns the new balance of the deposit. A withdrawal
+/// whose outcome is not known is retried before any new one. The caller
+/// must hold the guard of the owner.
+pub async fn withdraw_deposit(owner: Principal, amount_e8s: u64) -> Result<u64, String> {
+    if let Some(withdrawal) = get_pending_withdrawal(owner) {
+        send_withdrawal(owner, withdrawal).await?;
+    }
+    if amount_e8s <= ICP_TRANSFER_FEE_E8S {
+        return Err(format!(
+            "the withdrawn amount must exceed the transfer fee of {ICP_TRANSFER_FEE_E8S} e8s"
+        ));
+    }
+    let balance = get_deposit(owner);
+    if amount_e8s > balance {
+        return Err(format!(
+            "the deposit of {balance} e8s cannot pay a withdrawal of {amount_e8s} e8s"
+        ));
+    }
+
+    set_deposit(owner, balance - amount_e8s);
+    let withdrawal = PendingTransfer {
+        amount_e8s,
+        created_at_time: ic_cdk::api::time(),
+    };
+    insert_pending_withdrawal(owner, withdrawal.clone());
+    send_withdrawal(owner, withdrawal).await?;
+    Ok(get_deposit(owner))
+}
+
+async fn send_withdrawal(owner: Principal, withdrawal: PendingTransfer) -> Result<(), String> {
+    let result = transfer_from_subaccount(
+        Some(deposit_subaccount(owner).0),
+        owner,
+        Nat::from(withdrawal.amount_e8s - ICP_TRANSFER_FEE_E8S),
+        Some(Nat::from(ICP_TRANSFER_FEE_E8S)),
+        None,
+        Some(withdrawal.created_at_time),
+        MAINNET_LEDGER_CANISTER_ID,
+    )
+    .await;
+    match TransferOutcome::from(result) {
+        TransferOutcome::Done { block_index } => {
+            remove_pending_withdrawal(owner);
+            process_event(EventType::IcpWithdrawn {
+                owner,
+                amount_e8s: withdrawal.amount_e8s,
+                block_index,
+            });
+            Ok(())
+        }
+        TransferOutcome::Rejected { error } => {
+            remove_pending_withdrawal(owner);
+            set_deposit(
+                owner,
+                get_deposit(owner).saturating_add(withdrawal.amount_e8s),
+            );
+            Err(error)
+        }
+        TransferOutcome::Unknown { error } => Err(format!(
+            "the withdrawal of {} e8s is pending, call again to retry it: {error}",
+            withdrawal.amount_e8s
+        )),
+        TransferOutcome::Expired => {
+            remove_pending_withdrawal(owner);
+            Err(format!(
+                "the outcome of the withdrawal of {} e8s is unknown and can no longer be retried",
+                withdrawal.amount_e8s
+            ))
+        }
+    }
+}
+
+/// What a transfer from a deposit with a fixed `created_at_time` did.
+enum TransferOutcome {
+    Done {
+        block_index: u64,
+    },
+    /// The ledger refused the transfer, nothing left the deposit.
+    Rejected {
+        error: String,
+    },
+    /// The call failed, the transfer may have happened and must be retried
+    /// with the same arguments.
+    Unknown {
+        error: String,
+    },
+    /// The ledger no longer deduplicates a retry, whether an earlier
+    /// attempt happened cannot be told. The deposit is not restored.
+    Expired,
+}
+
+impl From<Result<u64, SubaccountTransferError>> for TransferOutcome {
+    fn from(result: Result<u64, SubaccountTransferError>) -> Self {
+        match result {
+            Ok(block_index) => Self::Done { block_index },
+            Err(e @ SubaccountTransferError::CallFailed { .. }) => Self::Unknown {
+                error: format!("{e:?}"),
+            },
+            Err(SubaccountTransferError::Ledger(TransferError::Duplicate { duplicate_of })) => {
+                Self::Done {
+                    block_index: duplicate_of.0.try_into().unwrap(),
+                }
+            }
+            Err(SubaccountTransferError::Ledger(TransferError::TooOld)) => Self::Expired,
+            Err(SubaccountTransferError::Ledger(e)) => Self::Rejected {
+                error: format!("{e:?}"),
+            },
+        }
+    }
+}
+
+/// Tops up the active miners whose last check, made after their last
+/// top-up, found fewer cycles than their threshold. Stops the miners
+/// whose owner can no longer pay. Owners with a call in progress are left
+/// to the next run.
+pub async fn run_auto_top_ups() {
+    for mut auto_top_up in get_auto_top_ups() {
+        // A stopped top-up still finishes its pending transfer.
+        if !auto_top_up.is_active() && !auto_top_up.has_pending() {
+            continue;
+        }
+        let _guard = match GuardPrincipal::new(auto_top_up.owner) {
+            Ok(guard) => guard,
+            Err(_) => continue,
+        };
+        if auto_top_up.pending_block_index.is_none() {
+            if auto_top_up.pending_transfer.is_none() {
+                if get_miner_owner(auto_top_up.miner) != Some(auto_top_up.owner) {
+                    auto_top_up.stop("the miner no longer belongs to the owner".to_string());
+                    insert_auto_top_up(auto_top_up);
+                    continue;
+                }
+                let is_low = get_miner_health(auto_top_up.miner).is_some_and(|health| {
+                    health
+                        .checked_at
+                        .is_some_and(|checked_at| checked_at > auto_top_up.last_top_up_at)
+                        && health.is_low_on_cycles(auto_top_up.threshold_cycles)
+                });
+                if !is_low {
+                    continue;
+                }
+                let balance = get_deposit(auto_top_up.owner);
+                if balance < auto_top_up.cost_e8s() {
+                    auto_top_up.stop(format!(
+                        "the deposit of {balance} e8s cannot pay a top-up of {} e8s",
+                        auto_top_up.cost_e8s()
+                    ));
+                    insert_auto_top_up(auto_top_up);
+                    continue;
+                }
+            }
+            if let Err(error) = send_to_cycles_minter(&mut auto_top_up).await {
+                auto_top_up.last_error = Some(error);
+                insert_auto_top_up(auto_top_up);
+                continue;
+            }
+        }
+        notify(&mut auto_top_up).await;
+        insert_auto_top_up(auto_top_up);
+    }
+}
+
+/// Transfers the top-up amount from the deposit of the owner to the top-up
+/// account of the miner at the cycles minting canister. The deposit must
+/// cover the cost, which is deducted before the first attempt and restored
+/// only if the ledger refuses the transfer. A transfer whose outcome is not
+/// known is retried with the same arguments.
+async fn send_to_cycles_minter(auto_top_up: &mut AutoTopUp) -> Result<(), String> {
+    let owner = auto_top_up.owner;
+    let transfer = match auto_top_up.pending_transfer.clone() {
+        Some(transfer) => transfer,
+        None => {
+            let transfer = PendingTransfer {
+                amount_e8s: auto_top_up.cost_e8s(),
+                created_at_time: ic_cdk::api::time(),
+            };
+            set_deposit(
+                owner,
+                get_deposit(owner).saturating_sub(transfer.amount_e8s),
+            );
+            auto_top_up.pending_transfer = Some(transfer.clone());
+            insert_auto_top_up(auto_top_up.clone());
+            transfer
+        }
+    };
+    let amount_e8s = transfer.amount_e8s - ICP_TRANSFER_FEE_E8S;
+
+    let result = transfer_from_subaccount(
+        Some(deposit_subaccount(owner).0),
+        Account {
+            owner: MAINNET_CYCLE_MINTER_CANISTER_ID,
+            subaccount: Some(Subaccount::from(&PrincipalId(auto_top_up.miner)).0),
+        },
+        Nat::from(amount_e8s),
+        Some(Nat::from(ICP_TRANSFER_FEE_E8S)),
+        Some(Memo::from(TOP_UP_MEMO.to_le_bytes().to_vec())),
+        Some(transfer.created_at_time),
+        MAINNET_LEDGER_CANISTER_ID,
+    )
+    .await;
+    match TransferOutcome::from(result) {
+        TransferOutcome::Done { block_index } => {
+            let now = ic_cdk::api::time();
+            auto_top_up.pending_transfer = None;
+            auto_top_up.pending_block_index = Some(block_index);
+            auto_top_up.last_top_up_at = now;
+            insert_top_up(MinerTopUp {
+                miner: auto_top_up.miner,
+                block_index,
+                payer: ic_cdk::id(),
+                amount_e8s,
+                created_at: now,
+                status: TopUpStatus::Pending,
+                held_by_minter: false,
+            });
+            Ok(())
+        }
+        TransferOutcome::Rejected { error } => {
+            auto_top_up.pending_transfer = None;
+            set_deposit(
+                owner,
+                get_deposit(owner).saturating_add(transfer.amount_e8s),
+            );
+            Err(error)
+        }
+        TransferOutcome::Unknown { error } => Err(error),
+        TransferOutcome::Expired => {
+            auto_top_up.pending_transfer = None;
+            let reason = format!(
+                "the outcome of the transfer of {amount_e8s} e8s to the cycles minting canister is unknown"
+            );
+            auto_top_up.stop(reason.clone());
+            Err(reason)
+        }
+    }
+}
+
+/// Asks the cycles minting canister to convert the pending transfer.
+async fn notify(auto_top_up: &mut AutoTopUp) {
+    let block_index = match auto_top_up.pending_block_index {
+        Some(block_index) => block_index,
+        None => return,
+    };
+    let mut top_up = match get_top_up(auto_top_up.miner, block_index) {
+        Some(top_up) => top_up,
+        None => return,
+    };
+    if matches!(
+        top_up.status,
+        TopUpStatus::ToppedUp { .. } | TopUpStatus::Refunded { .. }
+    ) {
+        auto_top_up.pending_block_index = None;
+        return;
+    }
+
+    match notify_top_up(block_index, auto_top_up.miner).await {
+        Ok(cycles) => {
+            top_up.status = TopUpStatus::ToppedUp {
+                cycles: cycles.get(),
+            };
+            process_event(EventType::MinerToppedUp {
+                miner: auto_top_up.miner,
+                block_index,
+                amount_e8s: top_up.amount_e8s,
+                cycles: cycles.get(),
+            });
+            auto_top_up.pending_block_index = None;
+            auto_top_up.last_error = None;
+        }
+        Err(NotifyError::Refunded { reason, .. }) => {
+            // The cycles minting canister sent the amount minus the fee
+            // back to the deposit.
+            let owner = auto_top_up.owner;
+            let refunded_e8s = top_up.amount_e8s.saturating_sub(ICP_TRANSFER_FEE_E8S);
+            set_deposit(owner, get_deposit(owner).saturating_add(refunded_e8s));
+            top_up.status = TopUpStatus::Refunded {
+                reason: reason.clone(),
+            };
+            auto_top_up.pending_block_index = None;
+            auto_top_up.last_error = Some(reason);
+        }
+        Err(e) => {
+            top_up.status = TopUpStatus::Failed {
+                error: e.to_string(),
+            };
+            auto_top_up.last_error = Some(e.to_string());
+        }
+    }
+    insert_top_up(top_up);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_failed_calls_are_unknown() {
+        let call_failed = SubaccountTransferError::CallFailed {
+            code: 2,
+            message: "timeout".to_string(),
+        };
+        assert!(matches!(
+            TransferOutcome::from(Err(call_failed)),
+            TransferOutcome::Unknown { .. }
+        ));
+        let generic_error = TransferError::GenericError {
+            error_code: Nat::from(1_u8),
+            message: "error".to_string(),
+        };
+        assert!(matches!(
+            TransferOutcome::from(Err(SubaccountTransferError::Ledger(generic_error))),
+            TransferOutcome::Rejected { .. }
+        ));
+        assert!(matches!(
+            TransferOutcome::from(Err(SubaccountTransferError::Ledger(TransferError::TooOld))),
+            TransferOutcome::Expired
+        ));
+        assert!(matches!(
+            TransferOutcome::from(Err(SubaccountTransferError::Ledger(
+                TransferError::Duplicate {
+                    duplicate_of: Nat::from(7_u8)
+                }
+            ))),
+            TransferOutcome::Done { block_index: 7 }
+        ));
+    }
+}
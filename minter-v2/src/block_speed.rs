@@ -0,0 +1,184 @@
+use crate::SEC_NANOS;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// Longest window covered by the statistics, longer windows are shortened
+/// to it.
+pub const MAX_WINDOW_SECS: u64 = 30 * 24 * 60 * 60;
+/// Shortest interval between two blocks, see `next_block_time`.
+const MIN_BLOCK_INTERVAL_SECS: u64 = 400;
+/// Number of most recent block timestamps kept in the state, enough to
+/// cover `MAX_WINDOW_SECS` at the shortest block interval.
+pub const MAX_TRACKED_BLOCKS: usize = (MAX_WINDOW_SECS / MIN_BLOCK_INTERVAL_SECS) as usize + 1;
+
+#[derive(Clone, Copy, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub enum BlockWindow {
+    LastBlocks(u64),
+    LastSeconds(u64),
+}
+
+impl BlockWindow {
+    /// The window shortened to what the tracked blocks cover.
+    fn clamped(self) -> Self {
+        match self {
+            Self::LastBlocks(count) => Self::LastBlocks(count.min(MAX_TRACKED_BLOCKS as u64)),
+            Self::LastSeconds(secs) => Self::LastSeconds(secs.min(MAX_WINDOW_SECS)),
+        }
+    }
+}
+
+pub const DEFAULT_WINDOWS: [BlockWindow; 2] = [
+    BlockWindow::LastBlocks(100),
+    BlockWindow::LastSeconds(24 * 60 * 60),
+];
+
+/// Statistics over the intervals between consecutive blocks, in seconds.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct BlockSpeedStats {
+    pub window: BlockWindow,
+    pub block_count: u64,
+    pub mean_secs: u64,
+    pub median_secs: u64,
+    pub p95_secs: u64,
+}
+
+/// Sorted timestamps of the most recently mined blocks.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, Default)]
+pub struct BlockTimes {
+    timestamps: Vec<u64>,
+}
+
+impl BlockTimes {
+    pub fn from_timestamps(mut timestamps: Vec<u64>) -> Self {
+        timestamps.sort_unstable();
+        let excess = timestamps.len().saturating_sub(MAX_TRACKED_BLOCKS);
+        timestamps.drain(..excess);
+        Self { timestamps }
+    }
+
+    pub fn record(&mut self, timestamp: u64) {
+        let position = self.timestamps.partition_point(|ts| *ts <= timestamp);
+        self.timestamps.insert(position, timestamp);
+        if self.timestamps.len() > MAX_TRACKED_BLOCKS {
+            self.timestamps.remove(0);
+        }
+    }
+
+    fn window(&self, window: BlockWindow, now: u64) -> &[u64] {
+        match window {
+            BlockWindow::LastBlocks(count) => {
+                let count = (count as usize).min(self.timestamps.len());
+                &self.timestamps[self.timestamps.len() - count..]
+            }
+            BlockWindow::LastSeconds(secs) => {
+                let from = now.saturating_sub(secs.saturating_mul(SEC_NANOS));
+                let start = self.timestamps.partition_point(|ts| *ts < from);
+                &self.timestamps[start..]
+            }
+        }
+    }
+
+    /// Statistics over the window, which is shortened to what the tracked
+    /// blocks cover. The returned window is the one actually used.
+    pub fn stats(&self, window: BlockWindow, now: u64) -> BlockSpeedStats {
+        let window = window.clamped();
+        let timestamps = self.window(window, now);
+        let mut intervals: Vec<u64> = timestamps
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]) / SEC_NANOS)
+            .collect();
+        intervals.sort_unstable();
+
+        let mean_secs = if intervals.is_empty() {
+            0
+        } else {
+            intervals.iter().sum::<u64>() / intervals.len() as u64
+        };
+
+        BlockSpeedStats {
+            window,
+            block_count: timestamps.len() as u64,
+            mean_secs,
+            median_secs: percentile(&intervals, 50),
+            p95_secs: percentile(&intervals, 95),
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[u64], p: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p * sorted.len() + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_over_last_blocks() {
+        let times = BlockTimes::from_timestamps(
+            [0, 400, 820, 1_240, 1_700, 2_100]
+                .into_iter()
+                .map(|secs| secs * SEC_NANOS)
+                .collect(),
+        );
+        let now = 2_200 * SEC_NANOS;
+
+        assert_eq!(
+            times.stats(BlockWindow::LastBlocks(100), now),
+            BlockSpeedStats {
+                window: BlockWindow::LastBlocks(100),
+                block_count: 6,
+                mean_secs: 420,
+                median_secs: 420,
+                p95_secs: 460,
+            }
+        );
+        assert_eq!(times.stats(BlockWindow::LastBlocks(2), now).mean_secs, 400);
+        assert_eq!(
+            times
+                .stats(BlockWindow::LastSeconds(1_000), now)
+                .block_count,
+            3
+        );
+    }
+
+    #[test]
+    fn test_empty_and_single_block_windows() {
+        let mut times = BlockTimes::default();
+        assert_eq!(times.stats(BlockWindow::LastBlocks(100), 0).block_count, 0);
+
+        times.record(5 * SEC_NANOS);
+        let stats = times.stats(BlockWindow::LastSeconds(60), 10 * SEC_NANOS);
+        assert_eq!(stats.block_count, 1);
+        assert_eq!(stats.mean_secs, 0);
+        assert_eq!(stats.p95_secs, 0);
+    }
+
+    #[test]
+    fn test_windows_are_clamped_to_tracked_blocks() {
+        let times = BlockTimes::default();
+        assert_eq!(
+            times.stats(BlockWindow::LastSeconds(u64::MAX), 0).window,
+            BlockWindow::LastSeconds(MAX_WINDOW_SECS)
+        );
+        assert_eq!(
+            times.stats(BlockWindow::LastBlocks(u64::MAX), 0).window,
+            BlockWindow::LastBlocks(MAX_TRACKED_BLOCKS as u64)
+        );
+    }
+
+    #[test]
+    fn test_record_keeps_order_and_bound() {
+        let mut times = BlockTimes::default();
+        for ts in (0..MAX_TRACKED_BLOCKS as u64 + 10).rev() {
+            times.record(ts * SEC_NANOS);
+        }
+        assert_eq!(times.timestamps.len(), MAX_TRACKED_BLOCKS);
+        assert!(times.timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+}
@@ -0,0 +1,82 @@
+use crate::memory::{get_block_hash, mined_block_count, user_count};
+use crate::read_state;
+use candid::{CandidType, Deserialize};
+use ic_certified_map::{AsHashTree, HashTree, RbTree};
+use icrc_ledger_types::icrc3::blocks::ICRC3DataCertificate;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+
+/// Statistics of the minter together with a certificate for the
+/// certified data of the canister and the hash tree covering them.
+#[derive(CandidType, Deserialize, Debug)]
+pub struct CertifiedStats {
+    pub last_block_index: Option<u64>,
+    pub last_block_hash: Option<ByteBuf>,
+    pub total_blocks_mined: u64,
+    pub current_rewards: u64,
+    pub pool_user_count: u64,
+    pub certificate: Option<ByteBuf>,
+    pub hash_tree: ByteBuf,
+}
+
+fn encode_u64(value: u64) -> Vec<u8> {
+    let mut buf = vec![];
+    leb128::write::unsigned(&mut buf, value).expect("failed to encode a certified value");
+    buf
+}
+
+fn certified_tree() -> RbTree<&'static str, Vec<u8>> {
+    let mut tree = RbTree::new();
+    if let Some(last_block_index) = mined_block_count().checked_sub(1) {
+        let last_block_hash =
+            get_block_hash(last_block_index).expect("bug: missing hash of the last block");
+        tree.insert("last_block_index", encode_u64(last_block_index));
+        tree.insert("last_block_hash", last_block_hash.to_vec());
+    }
+    let (total_blocks_mined, current_rewards) =
+        read_state(|s| (s.total_blocks_mined(), s.current_rewards()));
+    tree.insert("total_blocks_mined", encode_u64(total_blocks_mined));
+    tree.insert("current_rewards", encode_u64(current_rewards));
+    tree.insert("pool_user_count", encode_u64(user_count()));
+    tree
+}
+
+fn encode_hash_tree(tree: HashTree) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    tree.serialize(&mut serializer).unwrap();
+    serializer.into_inner()
+}
+
+/// Must be called whenever the tip of the block log, the mined block
+/// count or the pool membership changes.
+pub fn update_certified_data() {
+    ic_cdk::api::set_certified_data(&certified_tree().root_hash());
+}
+
+pub fn tip_certificate() -> Option<ICRC3DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let tree = certified_tree();
+    Some(ICRC3DataCertificate {
+        certificate: ByteBuf::from(certificate),
+        hash_tree: ByteBuf::from(encode_hash_tree(tree.as_hash_tree())),
+    })
+}
+
+pub fn certified_stats() -> CertifiedStats {
+    let tree = certified_tree();
+    let last_block_index = mined_block_count().checked_sub(1);
+    let (total_blocks_mined, current_rewards) =
+        read_state(|s| (s.total_blocks_mined(), s.current_rewards()));
+    CertifiedStats {
+        last_block_index,
+        last_block_hash: last_block_index
+            .and_then(get_block_hash)
+            .map(|hash| ByteBuf::from(hash.to_vec())),
+        total_blocks_mined,
+        current_rewards,
+        pool_user_count: user_count(),
+        certificate: ic_cdk::api::data_certificate().map(ByteBuf::from),
+        hash_tree: ByteBuf::from(encode_hash_tree(tree.as_hash_tree())),
+    }
+}
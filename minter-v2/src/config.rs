@@ -0,0 +1,199 @@
+use crate::memory::{get_config, set_config};
+use candid::{CandidType, Principal};
+use icp_ledger::AccountIdentifier;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+/// Memo of ICP transfers to the cycles minting canister that top up a canister.
+pub const TOP_UP_MEMO: u64 = 1347768404;
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub enum MinterArg {
+    Init(InitArg),
+    Upgrade(Option<UpgradeArg>),
+}
+
+/// Unset fields take the mainnet values.
+#[derive(Clone, CandidType, Deserialize, Debug, Default)]
+pub struct InitArg {
+    pub bob_ledger_id: Option<Principal>,
+    pub pool_id: Option<Principal>,
+    pub icp_index_id: Option<Principal>,
+    pub spawn_payment_accounts: Option<Vec<String>>,
+    pub pool_payment_account: Option<String>,
+    pub payment_memo: Option<u64>,
+}
+
+/// Unset fields keep their current value.
+#[derive(Clone, CandidType, Deserialize, Debug, Default)]
+pub struct UpgradeArg {
+    pub bob_ledger_id: Option<Principal>,
+    pub pool_id: Option<Principal>,
+    pub icp_index_id: Option<Principal>,
+    pub spawn_payment_accounts: Option<Vec<String>>,
+    pub pool_payment_account: Option<String>,
+    pub payment_memo: Option<u64>,
+}
+
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub bob_ledger_id: Principal,
+    pub pool_id: Principal,
+    pub icp_index_id: Principal,
+    /// Hex account identifiers accepted as destination of miner payments.
+    pub spawn_payment_accounts: Vec<String>,
+    /// Hex account identifier of pool membership payments.
+    pub pool_payment_account: String,
+    pub payment_memo: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bob_ledger_id: Principal::from_text("7pail-xaaaa-aaaas-aabmq-cai").unwrap(),
+            pool_id: Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap(),
+            icp_index_id: Principal::from_text("qhbym-qaaaa-aaaaa-aaafq-cai").unwrap(),
+            spawn_payment_accounts: vec![
+                "e7b583c3e3e2837c987831a97a6b980cbb0be89819e85915beb3c02006923fce".to_string(),
+                "6b896884e0b42634eca9c68c435c47b0ef2b97cf874a17198856b9c4efe89249".to_string(),
+            ],
+            pool_payment_account:
+                "e7b583c3e3e2837c987831a97a6b980cbb0be89819e85915beb3c02006923fce".to_string(),
+            payment_memo: TOP_UP_MEMO,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_init_arg(arg: InitArg) -> Self {
+        let mut config = Self::default();
+        config.update(
+            arg.bob_ledger_id,
+            arg.pool_id,
+            arg.icp_index_id,
+            arg.spawn_payment_accounts,
+            arg.pool_payment_account,
+            arg.payment_memo,
+        );
+        config
+    }
+
+    pub fn apply_upgrade_arg(&mut self, arg: UpgradeArg) {
+        self.update(
+            arg.bob_ledger_id,
+            arg.pool_id,
+            arg.icp_index_id,
+            arg.spawn_payment_accounts,
+            arg.pool_payment_account,
+            arg.payment_memo,
+        );
+    }
+
+    fn update(
+        &mut self,
+        bob_ledger_id: Option<Principal>,
+        pool_id: Option<Principal>,
+        icp_index_id: Option<Principal>,
+        spawn_payment_accounts: Option<Vec<String>>,
+        pool_payment_account: Option<String>,
+        payment_memo: Option<u64>,
+    ) {
+        if let Some(bob_ledger_id) = bob_ledger_id {
+            self.bob_ledger_id = bob_ledger_id;
+        }
+        if let Some(pool_id) = pool_id {
+            self.pool_id = pool_id;
+        }
+        if let Some(icp_index_id) = icp_index_id {
+            self.icp_index_id = icp_index_id;
+        }
+        if let Some(spawn_payment_accounts) = spawn_payment_accounts {
+            self.spawn_payment_accounts = spawn_payment_accounts;
+        }
+        if let Some(pool_payment_account) = pool_payment_account {
+            self.pool_payment_account = pool_payment_account;
+        }
+        if let Some(payment_memo) = payment_memo {
+            self.payment_memo = payment_memo;
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for account in self
+            .spawn_payment_accounts
+            .iter()
+            .chain(std::iter::once(&self.pool_payment_account))
+        {
+            AccountIdentifier::from_hex(account)
+                .map_err(|e| format!("invalid payment account {account}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    pub fn spawn_payment_accounts(&self) -> Vec<AccountIdentifier> {
+        self.spawn_payment_accounts
+            .iter()
+            .map(|account| AccountIdentifier::from_hex(account).unwrap())
+            .collect()
+    }
+
+    pub fn pool_payment_account(&self) -> AccountIdentifier {
+        AccountIdentifier::from_hex(&self.pool_payment_account).unwrap()
+    }
+}
+
+thread_local! {
+    static CONFIG: RefCell<Option<Config>> = RefCell::default();
+}
+
+/// Reads the configuration, loading it from stable memory on first use.
+pub fn read_config<R>(f: impl FnOnce(&Config) -> R) -> R {
+    CONFIG.with(|c| f(c.borrow_mut().get_or_insert_with(get_config)))
+}
+
+/// Validates and persists the configuration.
+pub fn replace_config(config: Config) {
+    if let Err(e) = config.validate() {
+        ic_cdk::trap(&e);
+    }
+    set_config(config.clone());
+    CONFIG.with(|c| *c.borrow_mut() = Some(config));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_is_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_upgrade_arg_keeps_unset_fields() {
+        let pool_id = Principal::from_slice(&[1; 29]);
+        let mut config = Config::from_init_arg(InitArg {
+            pool_id: Some(pool_id),
+            payment_memo: Some(42),
+            ..Default::default()
+        });
+        assert_eq!(config.pool_id, pool_id);
+        assert_eq!(config.bob_ledger_id, Config::default().bob_ledger_id);
+
+        config.apply_upgrade_arg(UpgradeArg {
+            payment_memo: Some(7),
+            ..Default::default()
+        });
+        assert_eq!(config.pool_id, pool_id);
+        assert_eq!(config.payment_memo, 7);
+    }
+
+    #[test]
+    fn test_invalid_payment_account_is_rejected() {
+        let config = Config::from_init_arg(InitArg {
+            pool_payment_account: Some("not hex".to_string()),
+            ..Default::default()
+        });
+        assert!(config.validate().is_err());
+    }
+}
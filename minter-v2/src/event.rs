@@ -0,0 +1,549 @@
+use crate::block_speed::BlockTimes;
+use crate::config::{read_config, Config};
+use crate::memory::{
+    append_event, event_count, get_block, get_block_to_mine, get_checkpoint,
+    get_events as read_events, get_miner_to_owner_and_index, get_round_transcripts,
+    mined_block_count, save_checkpoint,
+};
+use crate::{mutate_state, read_state, State, VersionedState};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of events returned by a single `get_events` call.
+pub const MAX_EVENTS_PER_RESPONSE: u64 = 2_000;
+/// The state is checkpointed at least every that many events, which bounds
+/// the number of events `replay_events` applies.
+const CHECKPOINT_INTERVAL: u64 = 1_000;
+/// Version of the lifetime statistics kept in the state. States saved with
+/// an older version are backfilled by `backfill_lifetime_stats`.
+pub const LIFETIME_STATS_VERSION: u64 = 1;
+/// Maximum number of round transcripts or events read by a single call of
+/// `backfill_lifetime_stats`.
+pub const MAX_BACKFILL_ITEMS: u64 = 500;
+
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub enum EventType {
+    Init {
+        config: Config,
+    },
+    Upgrade {
+        config: Config,
+    },
+    MinerSpawned {
+        miner: Principal,
+        owner: Principal,
+        block_index: u64,
+    },
+    PoolJoined {
+        owner: Principal,
+        block_index: u64,
+        amount_e8s: u64,
+        expiration: u64,
+    },
+    ChallengeSolved {
+        miner: Principal,
+        to: Principal,
+        total_cycles_burned: u64,
+        miner_cycles_burned: u64,
+    },
+    RewardPaid {
+        block_index: u64,
+        recipient: Principal,
+        amount: u64,
+        ledger_index: u64,
+        /// Whether the reward was a share of a block won by the pool.
+        #[serde(default)]
+        pool_share: bool,
+    },
+    RewardFailed {
+        block_index: u64,
+        recipient: Principal,
+        amount: u64,
+        error: String,
+    },
+    MinerTransferred {
+        miner: Principal,
+        from: Principal,
+        to: Principal,
+    },
+    /// A decommission withdrew the cycles of the miner, which is deleted by
+    /// the following `MinerDecommissioned`.
+    MinerCyclesWithdrawn {
+        miner: Principal,
+        owner: Principal,
+        cycles: u128,
+        cycles_to: Principal,
+    },
+    MinerDecommissioned {
+        miner: Principal,
+        owner: Principal,
+        /// Cycles the miner deposited to `cycles_to` before its deletion.
+        withdrawn_cycles: u128,
+        cycles_to: Option<Principal>,
+    },
+    PaymentRefunded {
+        block_index: u64,
+        recipient: Principal,
+        amount_e8s: u64,
+        ledger_index: u64,
+    },
+    MinerToppedUp {
+        miner: Principal,
+        block_index: u64,
+        amount_e8s: u64,
+        cycles: u128,
+    },
+    IcpDeposited {
+        owner: Principal,
+        amount_e8s: u64,
+        block_index: u64,
+    },
+    AutoTopUpStopped {
+        miner: Principal,
+        owner: Principal,
+        reason: String,
+    },
+    IcpWithdrawn {
+        owner: Principal,
+        /// Taken from the deposit, the transfer fee included.
+        amount_e8s: u64,
+        block_index: u64,
+    },
+}
+
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub timestamp: u64,
+    pub payload: EventType,
+}
+
+/// The state after the first `event_count` events of the log.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub event_count: u64,
+    pub state: VersionedState,
+}
+
+/// How far the backfill of the lifetime statistics got. Only the rounds and
+/// events recorded before it started are read, later ones update the
+/// statistics as they happen.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct StatsBackfill {
+    /// Timestamp of the next round transcript to read.
+    pub next_round: u64,
+    pub rounds_before: u64,
+    /// Index of the next event to read.
+    pub next_event: u64,
+    pub events_before: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GetEventsArg {
+    pub start: u64,
+    pub length: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GetEventsResult {
+    pub total_event_count: u64,
+    pub events: Vec<Event>,
+}
+
+/// Applies the state transition of an event. Events that only serve as
+/// an audit trail leave the state untouched. Submitted cycles are not
+/// logged, they are part of the checkpoint taken at the end of each round.
+pub fn apply_event(state: &mut State, event: &Event) {
+    match &event.payload {
+        EventType::MinerSpawned {
+            miner,
+            owner,
+            block_index,
+        } => state.new_miner(*miner, *owner, *block_index),
+        EventType::PoolJoined {
+            owner, amount_e8s, ..
+        } => state.join_pool(*owner, *amount_e8s),
+        EventType::MinerTransferred { miner, to, .. } => state.transfer_miner(*miner, *to),
+        EventType::MinerCyclesWithdrawn {
+            miner,
+            cycles,
+            cycles_to,
+            ..
+        } => {
+            state
+                .miner_to_withdrawal
+                .insert(*miner, (*cycles, *cycles_to));
+        }
+        EventType::MinerDecommissioned { miner, .. } => state.remove_miner(*miner),
+        EventType::ChallengeSolved { miner, .. } => state.challenge_solved(*miner, event.timestamp),
+        EventType::RewardPaid {
+            recipient,
+            amount,
+            pool_share,
+            ..
+        } => state.add_bob_earned(*recipient, *amount, *pool_share),
+        EventType::Init { .. }
+        | EventType::Upgrade { .. }
+        | EventType::RewardFailed { .. }
+        | EventType::PaymentRefunded { .. }
+        | EventType::MinerToppedUp { .. }
+        | EventType::IcpDeposited { .. }
+        | EventType::AutoTopUpStopped { .. }
+        | EventType::IcpWithdrawn { .. } => {}
+    }
+}
+
+/// Appends the event to the event log and applies it to the state.
+pub fn process_event(payload: EventType) {
+    let event = Event {
+        timestamp: ic_cdk::api::time(),
+        payload,
+    };
+    append_event(&event);
+    mutate_state(|s| apply_event(s, &event));
+    if event_count() % CHECKPOINT_INTERVAL == 0 {
+        checkpoint_state();
+    }
+}
+
+/// Saves the current state together with the length of the log, so that
+/// a replay only applies the events recorded after it.
+pub fn checkpoint_state() {
+    save_checkpoint(Checkpoint {
+        event_count: event_count(),
+        state: read_state(|s| VersionedState::V1(s.clone())),
+    });
+}
+
+/// Rebuilds the state from the last checkpoint and the events recorded
+/// after it. Cycles submitted since the last round end are lost.
+pub fn replay_events(now: u64) -> State {
+    let (mut state, mut start) = match get_checkpoint() {
+        Some(checkpoint) => (checkpoint.state.into_state(), checkpoint.event_count),
+        None => (State::new(now), 0),
+    };
+    while start < event_count() {
+        let events = read_events(start, MAX_EVENTS_PER_RESPONSE);
+        start += events.len() as u64;
+        for event in events {
+            apply_event(&mut state, &event);
+        }
+    }
+    state
+}
+
+/// Resets the lifetime statistics of a state saved by a version that did not
+/// keep them and starts recomputing them with `backfill_lifetime_stats`. The
+/// cycles of the running round are taken from the state. Returns whether a
+/// backfill is running.
+pub fn start_stats_backfill(state: &mut State, now: u64) -> bool {
+    if state.lifetime_stats_version < LIFETIME_STATS_VERSION {
+        state.lifetime_stats_version = LIFETIME_STATS_VERSION;
+        state.miner_to_lifetime_cycles.clear();
+        state.miner_to_last_submission.clear();
+        state.owner_to_pool_paid_e8s.clear();
+        state.owner_to_bob_earned_solo.clear();
+        state.owner_to_bob_earned_pool.clear();
+        let round_start = state.last_solved_challenge_ts;
+        for (miner, cycles) in state.miner_to_burned_cycles.clone() {
+            state.add_lifetime_cycles(miner, cycles, round_start);
+        }
+        state.stats_backfill = Some(StatsBackfill {
+            next_round: 0,
+            rounds_before: now,
+            next_event: 0,
+            events_before: event_count(),
+        });
+    }
+    state.stats_backfill.is_some()
+}
+
+/// Adds up to `max_items` round transcripts, then events, to the lifetime
+/// statistics. The last submission of a miner is taken as the end of the
+/// last round it took part in. Returns whether the backfill is done.
+pub fn backfill_lifetime_stats(max_items: u64) -> bool {
+    let mut backfill = match read_state(|s| s.stats_backfill.clone()) {
+        Some(backfill) => backfill,
+        None => return true,
+    };
+
+    if backfill.next_round < backfill.rounds_before {
+        let rounds = get_round_transcripts(backfill.next_round..backfill.rounds_before, max_items);
+        backfill.next_round = match rounds.last() {
+            Some((round_end, _)) if rounds.len() as u64 == max_items => round_end + 1,
+            _ => backfill.rounds_before,
+        };
+        mutate_state(|s| {
+            for (round_end, transcript) in rounds {
+                for participant in transcript.participants {
+                    s.add_lifetime_cycles(participant.miner, participant.burned_cycles, round_end);
+                }
+            }
+        });
+    } else if backfill.next_event < backfill.events_before {
+        let length = max_items.min(backfill.events_before - backfill.next_event);
+        let events = read_events(backfill.next_event, length);
+        backfill.next_event += length;
+        let pool_id = read_config(|c| c.pool_id);
+        mutate_state(|s| {
+            for event in events {
+                match event.payload {
+                    EventType::PoolJoined {
+                        owner, amount_e8s, ..
+                    } => s.join_pool(owner, amount_e8s),
+                    EventType::RewardPaid {
+                        block_index,
+                        recipient,
+                        amount,
+                        ..
+                    } => {
+                        // older events do not tell whether the reward was a pool share
+                        let pool_share =
+                            get_block(block_index).is_some_and(|block| block.to == pool_id);
+                        s.add_bob_earned(recipient, amount, pool_share);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    let done = backfill.next_round >= backfill.rounds_before
+        && backfill.next_event >= backfill.events_before;
+    mutate_state(|s| s.stats_backfill = (!done).then_some(backfill));
+    done
+}
+
+/// Reconstructs the state from the stable structures, for upgrades from
+/// versions that neither saved the state nor recorded events.
+pub fn rebuild_state(now: u64) -> State {
+    let mut state = State::new(now);
+
+    for (miner, (owner, index)) in get_miner_to_owner_and_index() {
+        state.new_miner(miner, owner, index);
+    }
+
+    let mut block_timestamps = vec![];
+    for index in 0..mined_block_count() {
+        if let Some(block) = get_block(index) {
+            if let Some(miner) = block.miner {
+                state
+                    .miner_to_mined_block
+                    .entry(miner)
+                    .and_modify(|e| *e += 1)
+                    .or_insert(1);
+                block_timestamps.push(block.timestamp);
+            }
+        }
+    }
+    block_timestamps.extend(get_block_to_mine().iter().map(|block| block.timestamp));
+    state.block_times = BlockTimes::from_timestamps(block_timestamps);
+    state
+}
+
+pub fn get_events(arg: GetEventsArg) -> GetEventsResult {
+    GetEventsResult {
+        total_event_count: event_count(),
+        events: read_events(arg.start, arg.length.min(MAX_EVENTS_PER_RESPONSE)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(timestamp: u64, payload: EventType) -> Event {
+        Event { timestamp, payload }
+    }
+
+    #[test]
+    fn test_apply_events() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let miner_1 = Principal::from_slice(&[2; 29]);
+        let miner_2 = Principal::from_slice(&[3; 29]);
+
+        let events = vec![
+            event(
+                1,
+                EventType::MinerSpawned {
+                    miner: miner_1,
+                    owner,
+                    block_index: 10,
+                },
+            ),
+            event(
+                2,
+                EventType::MinerSpawned {
+                    miner: miner_2,
+                    owner,
+                    block_index: 11,
+                },
+            ),
+            event(
+                4,
+                EventType::ChallengeSolved {
+                    miner: miner_1,
+                    to: owner,
+                    total_cycles_burned: 5,
+                    miner_cycles_burned: 5,
+                },
+            ),
+            event(
+                6,
+                EventType::RewardFailed {
+                    block_index: 0,
+                    recipient: owner,
+                    amount: 1,
+                    error: "error".to_string(),
+                },
+            ),
+            event(
+                7,
+                EventType::PoolJoined {
+                    owner,
+                    block_index: 12,
+                    amount_e8s: 300_000_000,
+                    expiration: 8,
+                },
+            ),
+            event(
+                8,
+                EventType::RewardPaid {
+                    block_index: 0,
+                    recipient: owner,
+                    amount: 1,
+                    ledger_index: 0,
+                    pool_share: false,
+                },
+            ),
+            event(
+                9,
+                EventType::RewardPaid {
+                    block_index: 1,
+                    recipient: owner,
+                    amount: 2,
+                    ledger_index: 1,
+                    pool_share: true,
+                },
+            ),
+        ];
+
+        let mut state = State::new(0);
+        for event in &events {
+            if event.timestamp == 4 {
+                state.submit_cycles(miner_1, 5, 3);
+            }
+            apply_event(&mut state, event);
+            if event.timestamp == 4 {
+                state.submit_cycles(miner_2, 7, 5);
+            }
+        }
+
+        assert_eq!(
+            state.principal_to_miner.get(&owner),
+            Some(&vec![miner_1, miner_2])
+        );
+        assert_eq!(state.miner_block_index.len(), 2);
+        assert_eq!(state.miner_to_mined_block.get(&miner_1), Some(&1));
+        assert_eq!(state.last_solved_challenge_ts, 4);
+        // lifetime statistics are kept across rounds
+        assert_eq!(state.miner_to_lifetime_cycles.get(&miner_1), Some(&5));
+        assert_eq!(state.miner_to_last_submission.get(&miner_2), Some(&5));
+        assert_eq!(state.owner_to_pool_paid_e8s.get(&owner), Some(&300_000_000));
+        assert_eq!(state.owner_to_bob_earned_solo.get(&owner), Some(&1));
+        assert_eq!(state.owner_to_bob_earned_pool.get(&owner), Some(&2));
+        // cycles submitted after the last solved challenge belong to the running round
+        assert_eq!(
+            state.miner_to_burned_cycles.into_iter().collect::<Vec<_>>(),
+            vec![(miner_2, 7)]
+        );
+    }
+
+    #[test]
+    fn test_transferred_miner_moves_to_new_owner() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let new_owner = Principal::from_slice(&[2; 29]);
+        let miner_1 = Principal::from_slice(&[3; 29]);
+        let miner_2 = Principal::from_slice(&[4; 29]);
+
+        let mut state = State::new(0);
+        for payload in [
+            EventType::MinerSpawned {
+                miner: miner_1,
+                owner,
+                block_index: 10,
+            },
+            EventType::MinerSpawned {
+                miner: miner_2,
+                owner,
+                block_index: 11,
+            },
+            EventType::MinerTransferred {
+                miner: miner_1,
+                from: owner,
+                to: new_owner,
+            },
+        ] {
+            apply_event(&mut state, &event(1, payload));
+        }
+
+        assert_eq!(state.miner_to_owner.get(&miner_1), Some(&new_owner));
+        assert_eq!(state.principal_to_miner.get(&owner), Some(&vec![miner_2]));
+        assert_eq!(
+            state.principal_to_miner.get(&new_owner),
+            Some(&vec![miner_1])
+        );
+    }
+
+    #[test]
+    fn test_decommissioned_miner_is_unregistered() {
+        let owner = Principal::from_slice(&[1; 29]);
+        let miner = Principal::from_slice(&[2; 29]);
+
+        let mut state = State::new(0);
+        apply_event(
+            &mut state,
+            &event(
+                1,
+                EventType::MinerSpawned {
+                    miner,
+                    owner,
+                    block_index: 10,
+                },
+            ),
+        );
+        state.submit_cycles(miner, 5, 1);
+        apply_event(
+            &mut state,
+            &event(
+                2,
+                EventType::MinerCyclesWithdrawn {
+                    miner,
+                    owner,
+                    cycles: 7,
+                    cycles_to: owner,
+                },
+            ),
+        );
+        // a retried decommission does not withdraw again
+        assert_eq!(state.miner_to_withdrawal.get(&miner), Some(&(7, owner)));
+        apply_event(
+            &mut state,
+            &event(
+                3,
+                EventType::MinerDecommissioned {
+                    miner,
+                    owner,
+                    withdrawn_cycles: 7,
+                    cycles_to: Some(owner),
+                },
+            ),
+        );
+
+        assert!(state.miner_to_owner.is_empty());
+        assert!(state.principal_to_miner.is_empty());
+        assert!(state.miner_to_burned_cycles.is_empty());
+        assert!(state.miner_to_withdrawal.is_empty());
+        // the payment stays consumed
+        assert!(state.miner_block_index.contains(&10));
+    }
+}
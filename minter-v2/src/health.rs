@@ -0,0 +1,125 @@
+use crate::config::read_config;
+use crate::memory::{
+    auto_top_up_count, get_miner_health, get_miner_owner, get_miners_after, insert_miner_health,
+};
+use crate::miner::canister_status;
+use crate::tasks::{schedule_after, schedule_now, TaskType};
+use candid::{CandidType, Principal};
+use ic_cdk::api::management_canister::main::CanisterStatusType;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::time::Duration;
+
+/// Miners checked per run of the monitoring task.
+pub const MONITOR_BATCH_SIZE: usize = 20;
+/// Delay between two batches of the same pass over the miners.
+const MONITOR_BATCH_DELAY: Duration = Duration::from_secs(10);
+/// Delay between two passes over all miners.
+pub const MONITOR_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Miners with fewer cycles are listed by `get_low_cycles_miners`. A miner
+/// burns at least 10B cycles per round, so this lasts about 50 rounds.
+pub const LOW_CYCLES_THRESHOLD: u128 = 500_000_000_000;
+
+thread_local! {
+    /// Last miner checked by the running pass, `None` at the start of a pass.
+    static MONITOR_CURSOR: Cell<Option<Principal>> = const { Cell::new(None) };
+}
+
+#[derive(Clone, Copy, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub enum MinerStatus {
+    Running,
+    Stopping,
+    Stopped,
+}
+
+impl From<CanisterStatusType> for MinerStatus {
+    fn from(status: CanisterStatusType) -> Self {
+        match status {
+            CanisterStatusType::Running => Self::Running,
+            CanisterStatusType::Stopping => Self::Stopping,
+            CanisterStatusType::Stopped => Self::Stopped,
+        }
+    }
+}
+
+/// The last known status of a miner. If the last check failed, the
+/// fields keep the result of the last successful check. They are `None`
+/// while the miner was never checked successfully.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct MinerHealth {
+    pub miner: Principal,
+    pub cycles: Option<u128>,
+    pub module_hash: Option<Vec<u8>>,
+    pub status: Option<MinerStatus>,
+    /// When the miner was last checked successfully.
+    pub checked_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl MinerHealth {
+    /// Miners with unknown cycles are not considered low.
+    pub fn is_low_on_cycles(&self, threshold: u128) -> bool {
+        self.cycles.is_some_and(|cycles| cycles < threshold)
+    }
+}
+
+/// Checks the next batch of miners and schedules the following one, or
+/// the next pass once all miners have been checked.
+pub async fn monitor_miners() {
+    let pool_id = read_config(|c| c.pool_id);
+    let miners = get_miners_after(MONITOR_CURSOR.with(|c| c.get()), MONITOR_BATCH_SIZE);
+
+    for miner in &miners {
+        // The pool is registered as a miner but is not a canister.
+        if *miner == pool_id {
+            continue;
+        }
+        let now = ic_cdk::api::time();
+        let health = match canister_status(*miner).await {
+            Ok(status) => MinerHealth {
+                miner: *miner,
+                cycles: Some(status.cycles.0.try_into().unwrap_or(u128::MAX)),
+                module_hash: status.module_hash,
+                status: Some(status.status.into()),
+                checked_at: Some(now),
+                last_error: None,
+            },
+            Err(e) => {
+                let error = format!("{} - {:?}", e.method, e.reason);
+                match get_miner_health(*miner) {
+                    Some(health) => MinerHealth {
+                        last_error: Some(error),
+                        ..health
+                    },
+                    // Never checked successfully, the miner may be deleted
+                    // or no longer controlled by the minter.
+                    None => MinerHealth {
+                        miner: *miner,
+                        cycles: None,
+                        module_hash: None,
+                        status: None,
+                        checked_at: None,
+                        last_error: Some(error),
+                    },
+                }
+            }
+        };
+        // The miner may have been decommissioned during the call.
+        if get_miner_owner(*miner).is_some() {
+            insert_miner_health(health);
+        }
+    }
+
+    // Top up the miners found low on cycles.
+    if auto_top_up_count() > 0 {
+        schedule_now(TaskType::AutoTopUps);
+    }
+
+    if miners.len() < MONITOR_BATCH_SIZE {
+        MONITOR_CURSOR.with(|c| c.set(None));
+        schedule_after(MONITOR_INTERVAL, TaskType::MonitorMiners);
+    } else {
+        MONITOR_CURSOR.with(|c| c.set(miners.last().copied()));
+        schedule_after(MONITOR_BATCH_DELAY, TaskType::MonitorMiners);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::config::read_config;
+use crate::MAINNET_LEDGER_CANISTER_ID;
+use candid::Nat;
+use ic_ledger_core::block::BlockType;
+use icp_ledger::{
+    Block, EncodedBlock, GetBlocksArgs, GetEncodedBlocksResult, QueryEncodedBlocksResponse,
+};
+use icrc_ledger_types::icrc3::blocks::GetBlocksRequest;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// Number of blocks requested from the index at once. Payments are usually
+/// verified shortly after they are made, so the blocks following the
+/// requested one are likely the next ones to be verified.
+const FETCH_BATCH_SIZE: u64 = 100;
+/// Maximum number of blocks kept in the cache, the oldest are evicted first.
+const MAX_CACHED_BLOCKS: usize = 1_000;
+
+thread_local! {
+    static BLOCK_CACHE: RefCell<BTreeMap<u64, Block>> = RefCell::default();
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FetchBlockError {
+    /// Neither the index nor the ledger have the block yet, retry later.
+    NotYetIndexed { block_index: u64 },
+    /// Both the index and the ledger could not be queried.
+    Unavailable { error: String },
+}
+
+/// Returns the ICP block at `block_index`.
+///
+/// Blocks are fetched in batches from the ICP index and cached. If the index
+/// has not synced the block yet, the ICP ledger and its archives are queried.
+pub async fn fetch_block(block_index: u64) -> Result<Block, FetchBlockError> {
+    if let Some(block) = get_cached_block(block_index) {
+        return Ok(block);
+    }
+
+    let index_error = match fetch_from_index(block_index).await {
+        Ok(()) => match get_cached_block(block_index) {
+            Some(block) => return Ok(block),
+            None => None,
+        },
+        Err(error) => Some(error),
+    };
+
+    match fetch_from_ledger(block_index).await {
+        Ok(Some(block)) => Ok(block),
+        Ok(None) => Err(FetchBlockError::NotYetIndexed { block_index }),
+        Err(ledger_error) => Err(FetchBlockError::Unavailable {
+            error: match index_error {
+                Some(index_error) => format!("{index_error}, {ledger_error}"),
+                None => ledger_error,
+            },
+        }),
+    }
+}
+
+async fn fetch_from_index(start: u64) -> Result<(), String> {
+    let args = GetBlocksRequest {
+        start: Nat::from(start),
+        length: Nat::from(FETCH_BATCH_SIZE),
+    };
+    let (response,): (ic_icp_index::GetBlocksResponse,) =
+        ic_cdk::call(read_config(|c| c.icp_index_id), "get_blocks", (args,))
+            .await
+            .map_err(|(code, msg)| {
+                format!("Error while calling the ICP index ({:?}): {}", code, msg)
+            })?;
+    cache_blocks(start, response.blocks)
+}
+
+/// Queries the ledger, and the archive holding the block if the ledger
+/// archived it. Returns `None` if the ledger does not have the block yet.
+async fn fetch_from_ledger(block_index: u64) -> Result<Option<Block>, String> {
+    let args = GetBlocksArgs {
+        start: block_index,
+        length: FETCH_BATCH_SIZE,
+    };
+    let (response,): (QueryEncodedBlocksResponse,) =
+        ic_cdk::call(MAINNET_LEDGER_CANISTER_ID, "query_encoded_blocks", (args,))
+            .await
+            .map_err(|(code, msg)| {
+                format!("Error while calling the ICP ledger ({:?}): {}", code, msg)
+            })?;
+
+    if block_index >= response.chain_length {
+        return Ok(None);
+    }
+
+    if let Some(range) = response
+        .archived_blocks
+        .into_iter()
+        .find(|range| range.start <= block_index && block_index < range.start + range.length)
+    {
+        let args = GetBlocksArgs {
+            start: block_index,
+            length: range.start + range.length - block_index,
+        };
+        let (result,): (GetEncodedBlocksResult,) =
+            ic_cdk::call(range.callback.canister_id, &range.callback.method, (args,))
+                .await
+                .map_err(|(code, msg)| {
+                    format!("Error while calling the ICP archive ({:?}): {}", code, msg)
+                })?;
+        let blocks = result.map_err(|e| format!("{e:?}"))?;
+        cache_blocks(block_index, blocks)?;
+    } else {
+        cache_blocks(response.first_block_index, response.blocks)?;
+    }
+
+    Ok(get_cached_block(block_index))
+}
+
+fn get_cached_block(block_index: u64) -> Option<Block> {
+    BLOCK_CACHE.with(|c| c.borrow().get(&block_index).cloned())
+}
+
+/// Caches the consecutive blocks starting at `start`.
+fn cache_blocks(start: u64, blocks: Vec<EncodedBlock>) -> Result<(), String> {
+    for (block_index, encoded) in (start..).zip(blocks) {
+        let block = Block::decode(encoded)?;
+        BLOCK_CACHE.with(|c| {
+            let mut cache = c.borrow_mut();
+            cache.insert(block_index, block);
+            while cache.len() > MAX_CACHED_BLOCKS {
+                cache.pop_first();
+            }
+        });
+    }
+    Ok(())
+}
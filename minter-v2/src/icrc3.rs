@@ -0,0 +1,173 @@
+use crate::memory::{get_block, get_block_hash, mined_block_count};
+use crate::Block;
+use candid::{Nat, Principal};
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use icrc_ledger_types::icrc3::blocks::{
+    BlockWithId, GetBlocksRequest, GetBlocksResult, SupportedBlockType,
+};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+
+pub type Hash = [u8; 32];
+
+pub const BLOCK_TYPE: &str = "bob_reward";
+pub const MAX_BLOCKS_PER_RESPONSE: u64 = 1_000;
+
+fn principal_value(principal: Principal) -> ICRC3Value {
+    ICRC3Value::Blob(ByteBuf::from(principal.as_slice().to_vec()))
+}
+
+fn nat_value(n: u64) -> ICRC3Value {
+    ICRC3Value::Nat(Nat::from(n))
+}
+
+/// Encodes a mined block as an ICRC-3 value. The reward recipient is
+/// encoded as an ICRC-1 account with the default subaccount.
+pub fn block_to_value(block: &Block, parent_hash: Option<Hash>) -> ICRC3Value {
+    let mut tx = BTreeMap::new();
+    tx.insert(
+        "to".to_string(),
+        ICRC3Value::Array(vec![principal_value(block.to)]),
+    );
+    tx.insert("amt".to_string(), nat_value(block.rewards));
+    if let Some(miner) = block.miner {
+        tx.insert("miner".to_string(), principal_value(miner));
+    }
+    if let Some(total_cycles_burned) = block.total_cycles_burned {
+        tx.insert("total_cycles".to_string(), nat_value(total_cycles_burned));
+    }
+    if let Some(miner_cycles_burned) = block.miner_cycles_burned {
+        tx.insert("miner_cycles".to_string(), nat_value(miner_cycles_burned));
+    }
+    if let Some(miner_count) = block.miner_count {
+        tx.insert("miner_count".to_string(), nat_value(miner_count));
+    }
+    if let Some(randomness) = &block.randomness {
+        tx.insert(
+            "randomness".to_string(),
+            ICRC3Value::Blob(ByteBuf::from(randomness.clone())),
+        );
+    }
+    if let Some(participants_hash) = &block.participants_hash {
+        tx.insert(
+            "participants_hash".to_string(),
+            ICRC3Value::Blob(ByteBuf::from(participants_hash.clone())),
+        );
+    }
+
+    let mut value = BTreeMap::new();
+    if let Some(parent_hash) = parent_hash {
+        value.insert(
+            "phash".to_string(),
+            ICRC3Value::Blob(ByteBuf::from(parent_hash.to_vec())),
+        );
+    }
+    value.insert(
+        "btype".to_string(),
+        ICRC3Value::Text(BLOCK_TYPE.to_string()),
+    );
+    value.insert("ts".to_string(), nat_value(block.timestamp));
+    value.insert("tx".to_string(), ICRC3Value::Map(tx));
+    ICRC3Value::Map(value)
+}
+
+pub fn block_hash(block: &Block, parent_hash: Option<Hash>) -> Hash {
+    block_to_value(block, parent_hash).hash()
+}
+
+fn get_block_value(index: u64) -> Option<ICRC3Value> {
+    let block = get_block(index)?;
+    let parent_hash = match index.checked_sub(1) {
+        Some(parent_index) => Some(get_block_hash(parent_index)?),
+        None => None,
+    };
+    Some(block_to_value(&block, parent_hash))
+}
+
+pub fn get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
+    let log_length = mined_block_count();
+    let mut blocks = vec![];
+
+    for arg in args {
+        let start = u64::try_from(arg.start.0).unwrap_or(u64::MAX);
+        let length = u64::try_from(arg.length.0).unwrap_or(u64::MAX);
+        let end = start.saturating_add(length).min(log_length);
+        for id in start..end {
+            if blocks.len() as u64 >= MAX_BLOCKS_PER_RESPONSE {
+                break;
+            }
+            if let Some(block) = get_block_value(id) {
+                blocks.push(BlockWithId {
+                    id: Nat::from(id),
+                    block,
+                });
+            }
+        }
+    }
+
+    GetBlocksResult {
+        log_length: Nat::from(log_length),
+        blocks,
+        archived_blocks: vec![],
+    }
+}
+
+pub fn supported_block_types() -> Vec<SupportedBlockType> {
+    vec![SupportedBlockType {
+        block_type: BLOCK_TYPE.to_string(),
+        url: "https://github.com/bob-robert-ai/bob".to_string(),
+    }]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(timestamp: u64) -> Block {
+        Block {
+            to: Principal::from_slice(&[0xFF; 29]),
+            miner: Some(Principal::from_slice(&[0xFE; 29])),
+            rewards: 60_000_000_000,
+            timestamp,
+            total_cycles_burned: Some(30_000_000_000),
+            miner_cycles_burned: Some(15_000_000_000),
+            miner_count: Some(2),
+            randomness: Some(vec![42; 32]),
+            participants_hash: None,
+        }
+    }
+
+    #[test]
+    fn test_block_hash_depends_on_parent_hash() {
+        let genesis = block(1);
+        let genesis_hash = block_hash(&genesis, None);
+        assert_eq!(genesis_hash, block_hash(&genesis, None));
+
+        let next = block(2);
+        let next_hash = block_hash(&next, Some(genesis_hash));
+        assert_ne!(next_hash, block_hash(&next, None));
+        assert_ne!(next_hash, block_hash(&next, Some([0; 32])));
+    }
+
+    #[test]
+    fn test_block_value_contains_parent_hash() {
+        let parent_hash = [7; 32];
+        match block_to_value(&block(1), Some(parent_hash)) {
+            ICRC3Value::Map(map) => {
+                assert_eq!(
+                    map.get("phash"),
+                    Some(&ICRC3Value::Blob(ByteBuf::from(parent_hash.to_vec())))
+                );
+                assert_eq!(
+                    map.get("btype"),
+                    Some(&ICRC3Value::Text(BLOCK_TYPE.to_string()))
+                );
+            }
+            value => panic!("unexpected block value: {value:?}"),
+        }
+        match block_to_value(&block(1), None) {
+            ICRC3Value::Map(map) => assert!(!map.contains_key("phash")),
+            value => panic!("unexpected block value: {value:?}"),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use crate::config::read_config;
+use crate::memory::{
+    get_activities, get_block, get_leaderboard_indexed_count, get_owner_activity,
+    get_round_transcript, insert_owner_activity, mined_block_count, set_leaderboard_indexed_count,
+};
+use crate::transcript::RoundTranscript;
+use crate::{read_state, Block, DAY_NANOS};
+use candid::{CandidType, Principal};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Bucket holding the activity since the first block.
+pub const ALL_TIME: u64 = u64::MAX;
+const HOUR_NANOS: u64 = DAY_NANOS / 24;
+/// Maximum number of entries returned by a single `get_leaderboard` call.
+pub const MAX_LEADERBOARD_PAGE: u64 = 100;
+/// Blocks indexed per call of `index_blocks`, which bounds the work of a
+/// single message while an existing log is indexed.
+pub const MAX_BLOCKS_PER_INDEXING: u64 = 500;
+
+/// What an owner did in an hour bucket.
+#[derive(Clone, Default, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct OwnerActivity {
+    pub blocks_won: u64,
+    pub bob_earned: u64,
+    pub cycles_burned: u64,
+}
+
+impl OwnerActivity {
+    fn add(&mut self, other: &OwnerActivity) {
+        self.blocks_won = self.blocks_won.saturating_add(other.blocks_won);
+        self.bob_earned = self.bob_earned.saturating_add(other.bob_earned);
+        self.cycles_burned = self.cycles_burned.saturating_add(other.cycles_burned);
+    }
+
+    /// Blocks won per trillion cycles burned.
+    pub fn win_rate(&self) -> f64 {
+        if self.cycles_burned == 0 {
+            return 0.0;
+        }
+        self.blocks_won as f64 * 1e12 / self.cycles_burned as f64
+    }
+}
+
+/// Windows are made of whole UTC hours, the current hour included.
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub enum LeaderboardWindow {
+    /// The last `n` times 24 hours, rolling by the hour: 1 for the last
+    /// 24 hours, 7 for the last week.
+    LastDays(u64),
+    /// The hours from `start` to `end` (timestamps in nanoseconds).
+    Season {
+        start: u64,
+        end: u64,
+    },
+    AllTime,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub enum LeaderboardMetric {
+    BlocksWon,
+    BobEarned,
+    CyclesBurned,
+    WinRate,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct LeaderboardArg {
+    pub window: LeaderboardWindow,
+    pub metric: LeaderboardMetric,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub struct RankedOwner {
+    /// Rank starting at 1.
+    pub rank: u64,
+    pub owner: Principal,
+    pub activity: OwnerActivity,
+    /// Blocks won per trillion cycles burned.
+    pub win_rate: f64,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq)]
+pub struct LeaderboardPage {
+    pub total_owners: u64,
+    pub entries: Vec<RankedOwner>,
+}
+
+/// Adds the activity of up to `max_blocks` blocks of the log that are not
+/// indexed yet to the hour buckets and the all-time bucket. Returns whether
+/// all blocks are indexed.
+pub fn index_blocks(max_blocks: u64) -> bool {
+    let pool_id = read_config(|c| c.pool_id);
+    let start = get_leaderboard_indexed_count();
+    let end = mined_block_count().min(start.saturating_add(max_blocks));
+    for index in start..end {
+        if let Some(block) = get_block(index) {
+            let transcript = get_round_transcript(block.timestamp);
+            let activities = read_state(|s| {
+                block_activity(&block, transcript.as_ref(), pool_id, |miner| {
+                    s.miner_to_owner.get(&miner).copied()
+                })
+            });
+            let hour = block.timestamp / HOUR_NANOS;
+            for (owner, activity) in activities {
+                for bucket in [hour, ALL_TIME] {
+                    let mut total = get_owner_activity(bucket, owner).unwrap_or_default();
+                    total.add(&activity);
+                    insert_owner_activity(bucket, owner, total);
+                }
+            }
+        }
+    }
+    set_leaderboard_indexed_count(end);
+    end == mined_block_count()
+}
+
+/// The activity of each owner in a block: the winner gets the block and the
+/// rewards, every participant of the round its burned cycles. Participants
+/// are credited to their owner at the end of the round, `owner_of` is only
+/// asked for transcripts that did not record it. Blocks without a
+/// transcript only credit the cycles of the winning miner. The pool is left
+/// out, it is not an owner that competes with the others.
+fn block_activity(
+    block: &Block,
+    transcript: Option<&RoundTranscript>,
+    pool_id: Principal,
+    owner_of: impl Fn(Principal) -> Option<Principal>,
+) -> BTreeMap<Principal, OwnerActivity> {
+    let mut activities: BTreeMap<Principal, OwnerActivity> = BTreeMap::new();
+    let winner = activities.entry(block.to).or_default();
+    winner.blocks_won = 1;
+    winner.bob_earned = block.rewards;
+
+    match transcript {
+        Some(transcript) => {
+            for participant in &transcript.participants {
+                if let Some(owner) = participant.owner.or_else(|| owner_of(participant.miner)) {
+                    let activity = activities.entry(owner).or_default();
+                    activity.cycles_burned = activity
+                        .cycles_burned
+                        .saturating_add(participant.burned_cycles);
+                }
+            }
+        }
+        None => {
+            if let Some(cycles) = block.miner_cycles_burned {
+                activities.entry(block.to).or_default().cycles_burned = cycles;
+            }
+        }
+    }
+    activities.remove(&pool_id);
+    activities
+}
+
+pub fn get_leaderboard(arg: LeaderboardArg, now: u64) -> LeaderboardPage {
+    let (first_hour, last_hour) = match arg.window {
+        LeaderboardWindow::LastDays(days) => {
+            let current_hour = now / HOUR_NANOS;
+            (
+                current_hour.saturating_sub(days.saturating_mul(24)),
+                current_hour,
+            )
+        }
+        LeaderboardWindow::Season { start, end } => (start / HOUR_NANOS, end / HOUR_NANOS),
+        LeaderboardWindow::AllTime => (ALL_TIME, ALL_TIME),
+    };
+
+    let mut totals: BTreeMap<Principal, OwnerActivity> = BTreeMap::new();
+    if first_hour <= last_hour {
+        for ((_, owner), activity) in get_activities(first_hour, last_hour) {
+            totals.entry(owner).or_default().add(&activity);
+        }
+    }
+    rank(
+        totals,
+        &arg.metric,
+        arg.offset,
+        arg.limit.min(MAX_LEADERBOARD_PAGE),
+    )
+}
+
+/// Sorts the owners by the metric, best first, ties broken by principal.
+fn rank(
+    totals: BTreeMap<Principal, OwnerActivity>,
+    metric: &LeaderboardMetric,
+    offset: u64,
+    limit: u64,
+) -> LeaderboardPage {
+    let mut ranked: Vec<(Principal, OwnerActivity)> = totals.into_iter().collect();
+    ranked.sort_by(|(owner_a, a), (owner_b, b)| {
+        let ordering = match metric {
+            LeaderboardMetric::BlocksWon => b.blocks_won.cmp(&a.blocks_won),
+            LeaderboardMetric::BobEarned => b.bob_earned.cmp(&a.bob_earned),
+            LeaderboardMetric::CyclesBurned => b.cycles_burned.cmp(&a.cycles_burned),
+            LeaderboardMetric::WinRate => b.win_rate().total_cmp(&a.win_rate()),
+        };
+        ordering.then(owner_a.cmp(owner_b))
+    });
+
+    LeaderboardPage {
+        total_owners: ranked.len() as u64,
+        entries: ranked
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|(position, (owner, activity))| RankedOwner {
+                rank: position as u64 + 1,
+                owner,
+                win_rate: activity.win_rate(),
+                activity,
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcript::Participant;
+
+    fn principal(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    fn activity(blocks_won: u64, bob_earned: u64, cycles_burned: u64) -> OwnerActivity {
+        OwnerActivity {
+            blocks_won,
+            bob_earned,
+            cycles_burned,
+        }
+    }
+
+    #[test]
+    fn test_block_activity_credits_all_participants() {
+        let block = Block {
+            to: principal(1),
+            miner: Some(principal(11)),
+            miner_cycles_burned: Some(5),
+            total_cycles_burned: Some(12),
+            timestamp: 0,
+            rewards: 100,
+            miner_count: Some(3),
+            randomness: None,
+            participants_hash: None,
+        };
+        let transcript = RoundTranscript {
+            randomness: vec![],
+            participants: vec![
+                Participant {
+                    miner: principal(11),
+                    burned_cycles: 5,
+                    owner: None,
+                },
+                Participant {
+                    miner: principal(12),
+                    burned_cycles: 3,
+                    owner: None,
+                },
+                Participant {
+                    miner: principal(21),
+                    burned_cycles: 4,
+                    owner: None,
+                },
+                Participant {
+                    miner: principal(22),
+                    burned_cycles: 6,
+                    owner: Some(principal(3)),
+                },
+            ],
+        };
+        // miners 11 and 12 belong to owner 1, miners 21 and 22 to owner 2,
+        // but miner 22 belonged to owner 3 at the end of the round
+        let owner_of = |miner: Principal| Some(principal(miner.as_slice()[0] / 10));
+
+        let pool_id = principal(9);
+        let activities = block_activity(&block, Some(&transcript), pool_id, owner_of);
+        assert_eq!(
+            activities.into_iter().collect::<Vec<_>>(),
+            vec![
+                (principal(1), activity(1, 100, 8)),
+                (principal(2), activity(0, 0, 4)),
+                (principal(3), activity(0, 0, 6)),
+            ]
+        );
+
+        let activities = block_activity(&block, None, pool_id, owner_of);
+        assert_eq!(
+            activities.into_iter().collect::<Vec<_>>(),
+            vec![(principal(1), activity(1, 100, 5))]
+        );
+
+        // the pool neither wins nor burns on the leaderboard
+        let pool_block = Block {
+            to: pool_id,
+            ..block
+        };
+        let transcript = RoundTranscript {
+            randomness: vec![],
+            participants: vec![
+                Participant {
+                    miner: pool_id,
+                    burned_cycles: 5,
+                    owner: Some(pool_id),
+                },
+                Participant {
+                    miner: principal(12),
+                    burned_cycles: 3,
+                    owner: None,
+                },
+            ],
+        };
+        let activities = block_activity(&pool_block, Some(&transcript), pool_id, owner_of);
+        assert_eq!(
+            activities.into_iter().collect::<Vec<_>>(),
+            vec![(principal(1), activity(0, 0, 3))]
+        );
+    }
+
+    #[test]
+    fn test_rank_by_metric_and_paginate() {
+        let totals: BTreeMap<Principal, OwnerActivity> = [
+            (principal(1), activity(3, 10, 4_000_000_000_000)),
+            (principal(2), activity(2, 30, 1_000_000_000_000)),
+            (principal(3), activity(2, 20, 0)),
+        ]
+        .into_iter()
+        .collect();
+
+        let owners = |page: LeaderboardPage| {
+            page.entries
+                .into_iter()
+                .map(|entry| (entry.rank, entry.owner))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            owners(rank(totals.clone(), &LeaderboardMetric::BlocksWon, 0, 10)),
+            vec![(1, principal(1)), (2, principal(2)), (3, principal(3))]
+        );
+        assert_eq!(
+            owners(rank(totals.clone(), &LeaderboardMetric::BobEarned, 1, 1)),
+            vec![(2, principal(3))]
+        );
+        assert_eq!(
+            owners(rank(totals.clone(), &LeaderboardMetric::WinRate, 0, 2)),
+            vec![(1, principal(2)), (2, principal(1))]
+        );
+
+        let page = rank(totals, &LeaderboardMetric::CyclesBurned, 5, 10);
+        assert_eq!(page.total_owners, 3);
+        assert!(page.entries.is_empty());
+    }
+}
@@ -1,21 +1,36 @@
+use crate::auto_top_up::run_auto_top_ups;
+use crate::block_speed::BlockTimes;
+use crate::certification::update_certified_data;
+use crate::config::read_config;
+use crate::event::{
+    backfill_lifetime_stats, checkpoint_state, process_event, EventType, StatsBackfill,
+    LIFETIME_STATS_VERSION, MAX_BACKFILL_ITEMS,
+};
 use crate::guard::TaskGuard;
+use crate::health::monitor_miners;
+use crate::leaderboard::{index_blocks, MAX_BLOCKS_PER_INDEXING};
 use crate::memory::{
-    get_block_to_mine, get_expire_map, get_miner_owner, insert_block_to_mine, push_block,
-    remove_block_to_mine, remove_expired_entries, should_mine, user_count,
+    get_block, get_block_to_mine, get_miner_owner, get_pool_reward_remainder, get_stake_map,
+    insert_block_to_mine, insert_payout, insert_round_transcript, mined_block_count,
+    outstanding_payout_count, push_block, remove_block_to_mine, remove_expired_entries,
+    set_pool_reward_remainder, should_mine, user_count,
 };
+use crate::payouts::{pay_due_rewards, split_pro_rata, Payout};
+use crate::selection::{select_winner, total_cycles};
+use crate::spawn::resume_pending_spawns;
 use crate::tasks::{schedule_after, schedule_now, TaskType};
+use crate::transcript::RoundTranscript;
 use candid::{CandidType, Decode, Encode, Nat, Principal};
 use cycles_minting_canister::NotifyError;
-use ic_ledger_core::block::BlockType;
+use ic_base_types::PrincipalId;
 use ic_types::Cycles;
 use icrc_ledger_client_cdk::{CdkRuntime, ICRC1Client};
 use icrc_ledger_types::icrc1::account::Account;
-use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
 use rand::distributions::Standard;
 use rand::rngs::StdRng;
-use rand::seq::SliceRandom;
 use rand::{Rng, SeedableRng};
-use rand_chacha::ChaCha20Rng;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::cell::RefCell;
@@ -28,6 +43,7 @@ pub const BLOCK_HALVING: u64 = 17_500;
 
 pub const SEC_NANOS: u64 = 1_000_000_000;
 pub const DAY_NANOS: u64 = 24 * 60 * 60 * SEC_NANOS;
+pub const E8S_PER_ICP: u64 = 100_000_000;
 
 const CYCLES_PER_USER_PER_ROUND: u64 = 15_000_000_000;
 
@@ -37,10 +53,27 @@ pub const MAINNET_LEDGER_CANISTER_ID: Principal =
 pub const MAINNET_CYCLE_MINTER_CANISTER_ID: Principal =
     Principal::from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x01, 0x01]);
 
+pub mod auto_top_up;
+pub mod block_speed;
+pub mod certification;
+pub mod config;
+pub mod event;
 pub mod guard;
+pub mod health;
+pub mod icp_blocks;
+pub mod icrc3;
+pub mod leaderboard;
 pub mod memory;
 pub mod miner;
+pub mod overview;
+pub mod payment;
+pub mod payouts;
+pub mod refund;
+pub mod selection;
+pub mod spawn;
 pub mod tasks;
+pub mod top_up;
+pub mod transcript;
 
 #[derive(Debug, Clone)]
 pub struct MinerWasm;
@@ -80,6 +113,46 @@ pub fn timer() {
                     let _ = mine_block().await;
                 });
             }
+            TaskType::ResumeSpawns => {
+                ic_cdk::spawn(async move {
+                    let _guard = match TaskGuard::new(task_type) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
+
+                    resume_pending_spawns().await;
+                });
+            }
+            TaskType::MonitorMiners => {
+                ic_cdk::spawn(async move {
+                    let _guard = match TaskGuard::new(task_type) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
+
+                    monitor_miners().await;
+                });
+            }
+            TaskType::AutoTopUps => {
+                ic_cdk::spawn(async move {
+                    let _guard = match TaskGuard::new(task_type) {
+                        Ok(guard) => guard,
+                        Err(_) => return,
+                    };
+
+                    run_auto_top_ups().await;
+                });
+            }
+            TaskType::IndexLeaderboard => {
+                if !index_blocks(MAX_BLOCKS_PER_INDEXING) {
+                    schedule_now(TaskType::IndexLeaderboard);
+                }
+            }
+            TaskType::BackfillStats => {
+                if !backfill_lifetime_stats(MAX_BACKFILL_ITEMS) {
+                    schedule_now(TaskType::BackfillStats);
+                }
+            }
             TaskType::ProcessLogic => {
                 ic_cdk::spawn(async move {
                     let _guard = match TaskGuard::new(task_type) {
@@ -104,6 +177,7 @@ pub fn timer() {
 
 fn burn_from_pool() {
     remove_expired_entries(ic_cdk::api::time());
+    update_certified_data();
     let user_count_u64 = user_count();
 
     if user_count_u64 == 0 {
@@ -114,14 +188,9 @@ fn burn_from_pool() {
 
     let burned_cycles = ic_cdk::api::cycles_burn(cycles_per_round as u128) as u64;
 
-    let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
+    let pool_id = read_config(|c| c.pool_id);
 
-    mutate_state(|s| {
-        s.miner_to_burned_cycles
-            .entry(pool_id)
-            .and_modify(|e| *e += burned_cycles)
-            .or_insert(burned_cycles);
-    });
+    mutate_state(|s| s.submit_cycles(pool_id, burned_cycles, ic_cdk::api::time()));
 }
 
 pub async fn process_logic() -> Result<(), String> {
@@ -129,40 +198,48 @@ pub async fn process_logic() -> Result<(), String> {
 
     if let Ok((random_array,)) = raw_rand().await {
         burn_from_pool();
-        let total_cycles: u64 = read_state(|s| s.miner_to_burned_cycles.values().sum());
+        let participants: Vec<(Principal, u64)> = read_state(|s| {
+            s.miner_to_burned_cycles
+                .iter()
+                .map(|(miner, cycles)| (*miner, *cycles))
+                .collect()
+        });
+        let total_cycles = total_cycles(&participants);
         if total_cycles == 0 {
             return Err("No cycles burned".to_string());
         }
 
-        let random_value = u64::from_le_bytes(random_array[..8].try_into().unwrap()) % total_cycles;
-
-        let selected_key = read_state(|s| {
-            let mut entries: Vec<_> = s.miner_to_burned_cycles.iter().collect();
-            let seed: [u8; 32] = random_array.clone().try_into().unwrap();
-            let mut rng = ChaCha20Rng::from_seed(seed);
-
-            entries.shuffle(&mut rng);
-
-            let mut cumulative_sum = 0;
-            entries
-                .into_iter()
-                .find(|(_, &value)| {
-                    cumulative_sum += value;
-                    cumulative_sum > random_value
-                })
-                .map(|(key, _)| *key)
-        })
-        .ok_or("No key selected")?;
+        let seed: [u8; 32] = random_array.try_into().unwrap();
+        let selected_key = select_winner(&participants, seed).ok_or("No key selected")?;
 
         if let Some(to) = get_miner_owner(selected_key) {
             let miner_cycles_burned =
                 read_state(|s| *s.miner_to_burned_cycles.get(&selected_key).unwrap_or(&0));
-            mutate_state(|s| {
-                s.challenge_solved(selected_key, to, total_cycles, miner_cycles_burned)
+            let transcript = RoundTranscript::new(seed, &participants).with_owners(get_miner_owner);
+            let now = ic_cdk::api::time();
+            insert_block_to_mine(Block {
+                miner: Some(selected_key),
+                to,
+                rewards: read_state(|s| s.current_rewards()),
+                timestamp: now,
+                total_cycles_burned: Some(total_cycles),
+                miner_cycles_burned: Some(miner_cycles_burned),
+                miner_count: Some(participants.len() as u64),
+                randomness: Some(transcript.randomness.clone()),
+                participants_hash: Some(transcript.participants_hash().to_vec()),
+            });
+            insert_round_transcript(now, transcript);
+            process_event(EventType::ChallengeSolved {
+                miner: selected_key,
+                to,
+                total_cycles_burned: total_cycles,
+                miner_cycles_burned,
             });
-            let next_block = next_block_time(random_array.try_into().unwrap());
+            update_certified_data();
+            let next_block = next_block_time(seed);
             schedule_now(TaskType::MineBob);
-            schedule_after(Duration::from_secs(next_block), TaskType::ProcessLogic);
+            schedule_round_end(Duration::from_secs(next_block));
+            checkpoint_state();
         } else {
             return Err("failed to find owner".to_string());
         }
@@ -173,117 +250,179 @@ pub async fn process_logic() -> Result<(), String> {
     Ok(())
 }
 
+/// Schedules the end of the current round and records it in the state,
+/// so that an upgrade does not restart the round.
+pub fn schedule_round_end(delay: Duration) {
+    let round_end = ic_cdk::api::time().saturating_add(delay.as_secs() * SEC_NANOS);
+    mutate_state(|s| s.next_round_ts = Some(round_end));
+    schedule_after(delay, TaskType::ProcessLogic);
+}
+
 pub async fn transfer(
     to: impl Into<Account>,
     amount: Nat,
     fee: Option<Nat>,
+    memo: Option<Memo>,
+    created_at_time: Option<u64>,
     ledger_canister_id: Principal,
 ) -> Result<u64, TransferError> {
+    transfer_from_subaccount(
+        None,
+        to,
+        amount,
+        fee,
+        memo,
+        created_at_time,
+        ledger_canister_id,
+    )
+    .await
+    .map_err(|e| match e {
+        SubaccountTransferError::CallFailed { code, message } => TransferError::GenericError {
+            error_code: Nat::from(code as u32),
+            message,
+        },
+        SubaccountTransferError::Ledger(e) => e,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SubaccountTransferError {
+    /// The call to the ledger failed, the transfer may have happened.
+    CallFailed { code: i32, message: String },
+    /// The ledger refused the transfer.
+    Ledger(TransferError),
+}
+
+pub async fn transfer_from_subaccount(
+    from_subaccount: Option<[u8; 32]>,
+    to: impl Into<Account>,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Memo>,
+    created_at_time: Option<u64>,
+    ledger_canister_id: Principal,
+) -> Result<u64, SubaccountTransferError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
         ledger_canister_id,
     };
     let block_index = client
         .transfer(TransferArg {
-            from_subaccount: None,
+            from_subaccount,
             to: to.into(),
             fee,
-            created_at_time: None,
-            memo: None,
+            created_at_time,
+            memo,
             amount,
         })
         .await
-        .map_err(|e| TransferError::GenericError {
-            error_code: (Nat::from(e.0 as u32)),
-            message: (e.1),
-        })??;
+        .map_err(|(code, message)| SubaccountTransferError::CallFailed { code, message })?
+        .map_err(SubaccountTransferError::Ledger)?;
     Ok(block_index.0.try_into().unwrap())
 }
 
 pub async fn mine_block() -> Result<(), String> {
-    if !should_mine() {
+    if !should_mine() && outstanding_payout_count() == 0 {
         return Err("nothing to do".to_string());
     }
 
-    let blocks = get_block_to_mine();
-    let ledger_canister_id = read_state(|s| s.bob_ledger_id);
-    for block in blocks {
-        let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
-        if block.to == pool_id {
-            let now = ic_cdk::api::time();
-            remove_expired_entries(now);
-            let user_count_u64 = user_count();
-            let reward = block.rewards / user_count_u64;
-            for (owner, _) in get_expire_map() {
-                if transfer(
-                    owner,
-                    reward.into(),
-                    Some(Nat::from(0_u8)),
-                    ledger_canister_id,
-                )
-                .await
-                .is_err()
-                {}
-            }
-            remove_block_to_mine(block.clone());
-            push_block(block);
-        } else {
-            match transfer(
-                block.to,
-                block.rewards.into(),
-                Some(Nat::from(0_u8)),
-                ledger_canister_id,
-            )
-            .await
-            {
-                Ok(_) => {
-                    remove_block_to_mine(block.clone());
-                    push_block(block);
-                }
-                Err(_e) => {
-                    schedule_after(Duration::from_secs(15), TaskType::MineBob);
-                }
-            }
-        }
+    for block in get_block_to_mine() {
+        log_block(block);
     }
+    pay_due_rewards().await;
     Ok(())
 }
 
+/// Appends a solved block to the block log and records the rewards owed
+/// to its recipients. Pool rewards are split among the pool members in
+/// proportion to their stake, the remainder is added to the next pool block.
+fn log_block(block: Block) {
+    let now = ic_cdk::api::time();
+    let pool_id = read_config(|c| c.pool_id);
+    let is_pool_block = block.to == pool_id;
+    let rewards: Vec<(Principal, u64)> = if is_pool_block {
+        remove_expired_entries(now);
+        let amount = block.rewards.saturating_add(get_pool_reward_remainder());
+        let (shares, remainder) = split_pro_rata(amount, &get_stake_map());
+        set_pool_reward_remainder(remainder);
+        shares
+    } else {
+        vec![(block.to, block.rewards)]
+    };
+
+    let block_timestamp = block.timestamp;
+    remove_block_to_mine(block.clone());
+    let block_index = push_block(block);
+    for (recipient, amount) in rewards {
+        if amount > 0 {
+            insert_payout(Payout {
+                pool_share: is_pool_block,
+                ..Payout::new(block_index, recipient, amount, block_timestamp, now)
+            });
+        }
+    }
+    if !index_blocks(MAX_BLOCKS_PER_INDEXING) {
+        schedule_now(TaskType::IndexLeaderboard);
+    }
+    update_certified_data();
+}
+
+/// Price of a miner, in e8s.
+pub const SPAWN_MINER_PRICE_E8S: u64 = E8S_PER_ICP;
+
+/// Pulls ICP approved by `from` (ICRC-2) into the top-up account of this
+/// canister at the cycles minting canister, with the memo the cycles minting
+/// canister expects. Returns the index of the ICP ledger block, which can
+/// then be passed to `notify_top_up`.
+pub async fn pull_top_up_payment(from: Principal, amount_e8s: u64) -> Result<u64, String> {
+    let top_up_subaccount = icp_ledger::Subaccount::from(&PrincipalId(ic_cdk::id()));
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account::from(from),
+        to: Account {
+            owner: MAINNET_CYCLE_MINTER_CANISTER_ID,
+            subaccount: Some(top_up_subaccount.0),
+        },
+        amount: Nat::from(amount_e8s),
+        fee: None,
+        memo: Some(Memo::from(
+            read_config(|c| c.payment_memo).to_le_bytes().to_vec(),
+        )),
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferFromError>,) =
+        ic_cdk::call(MAINNET_LEDGER_CANISTER_ID, "icrc2_transfer_from", (args,))
+            .await
+            .map_err(|(code, msg)| {
+                format!("Error while calling the ICP ledger ({:?}): {}", code, msg)
+            })?;
+    let block_index = result.map_err(|e| format!("{e:?}"))?;
+    Ok(block_index.0.try_into().unwrap())
+}
+
+/// Whether the ICP transfer carries the payment memo, either as legacy
+/// memo or as ICRC-1 memo (little-endian bytes) as used by `icrc2_transfer_from`.
+pub fn has_payment_memo(transaction: &icp_ledger::Transaction) -> bool {
+    let payment_memo = read_config(|c| c.payment_memo);
+    transaction.memo == icp_ledger::Memo(payment_memo)
+        || transaction
+            .icrc1_memo
+            .as_ref()
+            .is_some_and(|memo| memo.as_slice() == payment_memo.to_le_bytes())
+}
+
 #[derive(CandidType)]
 struct NotifyTopUp {
     block_index: u64,
     canister_id: Principal,
 }
 
-pub async fn fetch_block(block_height: u64) -> Result<icp_ledger::Block, String> {
-    let args = Encode!(&icrc_ledger_types::icrc3::blocks::GetBlocksRequest {
-        start: block_height.into(),
-        length: Nat::from(1_u8),
-    })
-    .unwrap();
-
-    let result: Result<Vec<u8>, (i32, String)> = ic_cdk::api::call::call_raw(
-        Principal::from_text("qhbym-qaaaa-aaaaa-aaafq-cai").unwrap(),
-        "get_blocks",
-        args,
-        0,
-    )
-    .await
-    .map_err(|(code, msg)| (code as i32, msg));
-    match result {
-        Ok(res) => {
-            let blocks = Decode!(&res, ic_icp_index::GetBlocksResponse).unwrap();
-            icp_ledger::Block::decode(blocks.blocks.first().expect("no block").clone())
-        }
-        Err((code, msg)) => Err(format!(
-            "Error while calling minter canister ({}): {:?}",
-            code, msg
-        )),
-    }
-}
-
-pub async fn notify_top_up(block_height: u64) -> Result<Cycles, String> {
-    let canister_id = ic_cdk::id();
+/// Asks the cycles minting canister to convert the ICP payment at
+/// `block_height` to cycles of `canister_id`.
+pub async fn notify_top_up(
+    block_height: u64,
+    canister_id: Principal,
+) -> Result<Cycles, NotifyError> {
     let args = Encode!(&NotifyTopUp {
         block_index: block_height,
         canister_id,
@@ -295,17 +434,11 @@ pub async fn notify_top_up(block_height: u64) -> Result<Cycles, String> {
             .await
             .map_err(|(code, msg)| (code as i32, msg));
     match res_gov {
-        Ok(res) => {
-            let decode = Decode!(&res, Result<Cycles, NotifyError>).unwrap();
-            match decode {
-                Ok(cycles) => Ok(cycles),
-                Err(e) => Err(format!("{e}")),
-            }
-        }
-        Err((code, msg)) => Err(format!(
-            "Error while calling minter canister ({}): {:?}",
-            code, msg
-        )),
+        Ok(res) => Decode!(&res, Result<Cycles, NotifyError>).unwrap(),
+        Err((code, msg)) => Err(NotifyError::Other {
+            error_code: code as u64,
+            error_message: format!("Error while calling minter canister: {:?}", msg),
+        }),
     }
 }
 
@@ -322,6 +455,97 @@ pub struct Block {
     pub total_cycles_burned: Option<u64>,
     pub miner_cycles_burned: Option<u64>,
     pub miner_count: Option<u64>,
+    pub randomness: Option<Vec<u8>>,
+    pub participants_hash: Option<Vec<u8>>,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, Default)]
+pub struct BlockFilter {
+    pub miner: Option<Principal>,
+    /// Matches the reward recipient `Block.to`.
+    pub owner: Option<Principal>,
+    pub from_timestamp: Option<u64>,
+    /// Exclusive upper bound of the timestamp window.
+    pub to_timestamp: Option<u64>,
+}
+
+impl BlockFilter {
+    pub fn matches(&self, block: &Block) -> bool {
+        self.miner.map_or(true, |miner| block.miner == Some(miner))
+            && self.owner.map_or(true, |owner| block.to == owner)
+            && self
+                .from_timestamp
+                .map_or(true, |from| block.timestamp >= from)
+            && self.to_timestamp.map_or(true, |to| block.timestamp < to)
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug)]
+pub struct BlockWithIndex {
+    pub index: u64,
+    pub block: Block,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GetBlocksResponse {
+    pub total_blocks: u64,
+    pub blocks: Vec<BlockWithIndex>,
+    /// Index of the block to pass as `start` to get the next page, if the
+    /// filter was not checked against all blocks yet.
+    pub next_start: Option<u64>,
+}
+
+pub const MAX_BLOCKS_PER_PAGE: u64 = 500;
+/// Blocks checked against the filter by a single `get_blocks` call.
+pub const MAX_SCANNED_BLOCKS: u64 = 10_000;
+
+/// Returns up to `length` blocks matching the filter, starting at the
+/// block index `start`. Blocks are returned in log order. At most
+/// `MAX_SCANNED_BLOCKS` blocks are checked per call, `next_start` tells
+/// where to continue.
+pub fn get_blocks(start: u64, length: u64, filter: BlockFilter) -> GetBlocksResponse {
+    let length = length.min(MAX_BLOCKS_PER_PAGE);
+    let total_blocks = mined_block_count();
+    // Blocks are logged in time order, the first block of the window is
+    // found by bisection.
+    let mut index = match filter.from_timestamp {
+        Some(from) => start.max(first_block_at_or_after(from, total_blocks)),
+        None => start,
+    };
+    let scan_end = total_blocks.min(index.saturating_add(MAX_SCANNED_BLOCKS));
+    let mut blocks = vec![];
+    while index < scan_end && (blocks.len() as u64) < length {
+        let block = match get_block(index) {
+            Some(block) => block,
+            None => break,
+        };
+        if filter.to_timestamp.is_some_and(|to| block.timestamp >= to) {
+            index = total_blocks;
+            break;
+        }
+        if filter.matches(&block) {
+            blocks.push(BlockWithIndex { index, block });
+        }
+        index += 1;
+    }
+    GetBlocksResponse {
+        total_blocks,
+        blocks,
+        next_start: (index < total_blocks).then_some(index),
+    }
+}
+
+/// Index of the first block with a timestamp of at least `timestamp`.
+fn first_block_at_or_after(timestamp: u64, total_blocks: u64) -> u64 {
+    let (mut low, mut high) = (0, total_blocks);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        match get_block(middle) {
+            Some(block) if block.timestamp < timestamp => low = middle + 1,
+            _ => high = middle,
+        }
+    }
+    low
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -333,12 +557,11 @@ pub struct Stats {
     pub cycle_balance: u64,
     pub time_since_last_block: u64,
     pub pending_blocks: Vec<Block>,
+    pub outstanding_payouts: u64,
 }
 
 #[derive(Clone, CandidType, Deserialize, Serialize, Debug)]
 pub struct State {
-    pub bob_ledger_id: Principal,
-
     pub miner_to_burned_cycles: BTreeMap<Principal, u64>,
 
     pub miner_to_mined_block: BTreeMap<Principal, u64>,
@@ -348,17 +571,65 @@ pub struct State {
 
     pub last_solved_challenge_ts: u64,
 
+    pub block_times: BlockTimes,
+
     pub miner_block_index: BTreeSet<u64>,
 
+    /// When the current round ends, if one is scheduled.
+    #[serde(default)]
+    pub next_round_ts: Option<u64>,
+
+    /// Cycles submitted by each miner over its lifetime.
+    #[serde(default)]
+    pub miner_to_lifetime_cycles: BTreeMap<Principal, u64>,
+    /// When each miner last submitted cycles.
+    #[serde(default)]
+    pub miner_to_last_submission: BTreeMap<Principal, u64>,
+    /// ICP paid by each owner for pool memberships.
+    #[serde(default)]
+    pub owner_to_pool_paid_e8s: BTreeMap<Principal, u64>,
+    /// BoB paid to each owner for the blocks won by its miners.
+    #[serde(default)]
+    pub owner_to_bob_earned_solo: BTreeMap<Principal, u64>,
+    /// BoB paid to each owner as share of the blocks won by the pool.
+    #[serde(default)]
+    pub owner_to_bob_earned_pool: BTreeMap<Principal, u64>,
+    /// Version of the lifetime statistics above, see `start_stats_backfill`.
+    #[serde(default)]
+    pub lifetime_stats_version: u64,
+    /// Progress of the backfill of the lifetime statistics, while it runs.
+    #[serde(default)]
+    pub stats_backfill: Option<StatsBackfill>,
+    /// Cycles withdrawn from miners whose decommission did not delete them
+    /// yet, and the canister that received them.
+    #[serde(default)]
+    pub miner_to_withdrawal: BTreeMap<Principal, (u128, Principal)>,
+
+    #[serde(skip)]
     pub principal_guards: BTreeSet<Principal>,
+    #[serde(skip)]
     pub active_tasks: BTreeSet<TaskType>,
 }
 
+/// The state as written to stable memory before an upgrade. Fields added
+/// to `State` with a serde default need no new version; any other change
+/// to the layout gets a new variant and a migration in `into_state`.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum VersionedState {
+    V1(State),
+}
+
+impl VersionedState {
+    pub fn into_state(self) -> State {
+        match self {
+            VersionedState::V1(state) => state,
+        }
+    }
+}
+
 impl State {
     pub fn new(now: u64) -> Self {
         Self {
-            bob_ledger_id: Principal::from_text("7pail-xaaaa-aaaas-aabmq-cai").unwrap(),
-
             miner_to_burned_cycles: BTreeMap::default(),
 
             miner_to_mined_block: BTreeMap::default(),
@@ -368,8 +639,21 @@ impl State {
 
             last_solved_challenge_ts: now,
 
+            block_times: BlockTimes::default(),
+
             miner_block_index: BTreeSet::default(),
 
+            next_round_ts: None,
+
+            miner_to_lifetime_cycles: BTreeMap::default(),
+            miner_to_last_submission: BTreeMap::default(),
+            owner_to_pool_paid_e8s: BTreeMap::default(),
+            owner_to_bob_earned_solo: BTreeMap::default(),
+            owner_to_bob_earned_pool: BTreeMap::default(),
+            lifetime_stats_version: LIFETIME_STATS_VERSION,
+            stats_backfill: None,
+            miner_to_withdrawal: BTreeMap::default(),
+
             active_tasks: BTreeSet::default(),
             principal_guards: BTreeSet::default(),
         }
@@ -393,6 +677,35 @@ impl State {
             .push(miner);
     }
 
+    /// Unregisters the miner. Its mined blocks are kept for the statistics.
+    pub fn remove_miner(&mut self, miner: Principal) {
+        if let Some(owner) = self.miner_to_owner.remove(&miner) {
+            self.remove_from_owner(miner, owner);
+        }
+        self.miner_to_burned_cycles.remove(&miner);
+        self.miner_to_withdrawal.remove(&miner);
+    }
+
+    /// Moves the miner to `new_owner`, which receives its future rewards.
+    pub fn transfer_miner(&mut self, miner: Principal, new_owner: Principal) {
+        if let Some(owner) = self.miner_to_owner.insert(miner, new_owner) {
+            self.remove_from_owner(miner, owner);
+        }
+        self.principal_to_miner
+            .entry(new_owner)
+            .or_default()
+            .push(miner);
+    }
+
+    fn remove_from_owner(&mut self, miner: Principal, owner: Principal) {
+        if let Some(miners) = self.principal_to_miner.get_mut(&owner) {
+            miners.retain(|m| *m != miner);
+            if miners.is_empty() {
+                self.principal_to_miner.remove(&owner);
+            }
+        }
+    }
+
     pub fn current_rewards(&self) -> u64 {
         COINBASE_REWARDS >> (self.total_blocks_mined() / BLOCK_HALVING)
     }
@@ -401,28 +714,43 @@ impl State {
         (ic_cdk::api::time() - self.last_solved_challenge_ts) / SEC_NANOS
     }
 
-    pub fn challenge_solved(
-        &mut self,
-        by: Principal,
-        to: Principal,
-        total_cycles_burned: u64,
-        cycles_burned: u64,
-    ) {
-        let rewards = self.current_rewards();
-        insert_block_to_mine(Block {
-            miner: Some(by),
-            to,
-            rewards,
-            timestamp: ic_cdk::api::time(),
-            total_cycles_burned: Some(total_cycles_burned),
-            miner_cycles_burned: Some(cycles_burned),
-            miner_count: Some(self.miner_to_burned_cycles.len() as u64),
-        });
+    pub fn submit_cycles(&mut self, miner: Principal, cycles: u64, timestamp: u64) {
+        self.miner_to_burned_cycles
+            .entry(miner)
+            .and_modify(|e| *e += cycles)
+            .or_insert(cycles);
+        self.add_lifetime_cycles(miner, cycles, timestamp);
+    }
+
+    pub fn add_lifetime_cycles(&mut self, miner: Principal, cycles: u64, timestamp: u64) {
+        let lifetime_cycles = self.miner_to_lifetime_cycles.entry(miner).or_default();
+        *lifetime_cycles = lifetime_cycles.saturating_add(cycles);
+        let last_submission = self.miner_to_last_submission.entry(miner).or_default();
+        *last_submission = (*last_submission).max(timestamp);
+    }
+
+    pub fn add_bob_earned(&mut self, owner: Principal, amount: u64, pool_share: bool) {
+        let earned = match pool_share {
+            true => &mut self.owner_to_bob_earned_pool,
+            false => &mut self.owner_to_bob_earned_solo,
+        }
+        .entry(owner)
+        .or_default();
+        *earned = earned.saturating_add(amount);
+    }
+
+    pub fn join_pool(&mut self, owner: Principal, amount_e8s: u64) {
+        let paid_e8s = self.owner_to_pool_paid_e8s.entry(owner).or_default();
+        *paid_e8s = paid_e8s.saturating_add(amount_e8s);
+    }
+
+    pub fn challenge_solved(&mut self, by: Principal, timestamp: u64) {
         self.miner_to_mined_block
             .entry(by)
             .and_modify(|e| *e += 1)
             .or_insert(1);
-        self.last_solved_challenge_ts = ic_cdk::api::time();
+        self.last_solved_challenge_ts = timestamp;
+        self.block_times.record(timestamp);
         self.miner_to_burned_cycles = BTreeMap::default();
     }
 }
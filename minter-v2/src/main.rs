@@ -1,62 +1,140 @@
+use bob_minter_v2::auto_top_up::{disable_auto_top_up, AutoTopUp, AutoTopUpPolicy};
+use bob_minter_v2::block_speed::{BlockSpeedStats, BlockWindow, DEFAULT_WINDOWS};
+use bob_minter_v2::certification::{
+    certified_stats, tip_certificate, update_certified_data, CertifiedStats,
+};
+use bob_minter_v2::config::{read_config, replace_config, Config, MinterArg};
+use bob_minter_v2::event::{
+    checkpoint_state, process_event, rebuild_state, replay_events, start_stats_backfill, EventType,
+    GetEventsArg, GetEventsResult,
+};
 use bob_minter_v2::guard::GuardPrincipal;
+use bob_minter_v2::health::{MinerHealth, LOW_CYCLES_THRESHOLD};
+use bob_minter_v2::leaderboard::{LeaderboardArg, LeaderboardPage};
 use bob_minter_v2::memory::{
-    get_block, get_block_to_mine, get_expiration, get_miner_owner, get_miner_to_owner_and_index,
-    get_user_expiration, insert_block_index, insert_expiration, insert_new_miner, is_known_block,
-    mined_block_count, user_count,
+    backfill_block_hashes, event_count, get_all_miner_health, get_block, get_block_to_mine,
+    get_expiration, get_miner_owner, get_payouts_of, get_pool_reward_remainder, get_refunds_of,
+    get_stake, get_top_ups_of, get_user_expiration, insert_block_index, insert_expiration,
+    insert_new_miner, insert_stake, is_known_block, migrate_stakes, mined_block_count,
+    outstanding_payout_count, remove_miner, save_state, set_miner_owner, take_state, total_stake,
+    user_count,
 };
 use bob_minter_v2::miner::{
-    create_canister, install_code, reinstall_code, start_canister, stop_canister,
+    delete_canister, set_owner, start_canister, stop_canister, upgrade_code, withdraw_cycles,
+    ManageMinerError,
 };
+use bob_minter_v2::overview::OwnerOverview;
+use bob_minter_v2::payment::{validate_payment, JoinPoolError, SpawnMinerError, MIN_PAYMENT_E8S};
+use bob_minter_v2::payouts::Payout;
+use bob_minter_v2::refund::{Refund, RefundError};
+use bob_minter_v2::spawn::{advance_spawn, start_spawn, SpawnRequest};
 use bob_minter_v2::tasks::{schedule_after, schedule_now, TaskType};
+use bob_minter_v2::top_up::{MinerTopUp, TopUpMinerError};
+use bob_minter_v2::transcript::RoundTranscript;
 use bob_minter_v2::{
-    fetch_block, miner_wasm, mutate_state, notify_top_up, read_state, replace_state, Block, State,
-    Stats, BLOCK_HALVING, DAY_NANOS, SEC_NANOS,
+    miner_wasm, mutate_state, notify_top_up, pull_top_up_payment, read_state, replace_state,
+    schedule_round_end, Block, BlockFilter, GetBlocksResponse, State, Stats, BLOCK_HALVING,
+    DAY_NANOS, E8S_PER_ICP, SEC_NANOS, SPAWN_MINER_PRICE_E8S,
 };
 use candid::{CandidType, Encode, Principal};
-use ic_cdk::{init, post_upgrade, query, update};
-use icp_ledger::{AccountIdentifier, Operation};
+use ic_cdk::{init, post_upgrade, pre_upgrade, query, update};
+use icrc_ledger_types::icrc3::archive::{GetArchivesArgs, ICRC3ArchiveInfo};
+use icrc_ledger_types::icrc3::blocks::{
+    GetBlocksRequest, GetBlocksResult, ICRC3DataCertificate, SupportedBlockType,
+};
 use std::time::Duration;
 
 fn main() {}
 
 #[post_upgrade]
-fn post_upgrade() {
-    let mut state = State::new(ic_cdk::api::time());
-
-    for (miner, (owner, index)) in get_miner_to_owner_and_index() {
-        state.new_miner(miner, owner, index);
+fn post_upgrade(minter_arg: Option<MinterArg>) {
+    match minter_arg {
+        Some(MinterArg::Init(_)) => ic_cdk::trap("cannot upgrade the minter with an init argument"),
+        Some(MinterArg::Upgrade(Some(upgrade_arg))) => {
+            let mut config = read_config(|c| c.clone());
+            config.apply_upgrade_arg(upgrade_arg);
+            replace_config(config);
+        }
+        Some(MinterArg::Upgrade(None)) | None => {}
     }
 
-    for index in 0..mined_block_count() {
-        if let Some(block) = get_block(index) {
-            if let Some(miner) = block.miner {
-                state
-                    .miner_to_mined_block
-                    .entry(miner)
-                    .and_modify(|e| *e += 1)
-                    .or_insert(1);
-            }
+    let now = ic_cdk::api::time();
+    let mut state = take_state().unwrap_or_else(|| {
+        if event_count() == 0 {
+            rebuild_state(now)
+        } else {
+            replay_events(now)
         }
+    });
+    if start_stats_backfill(&mut state, now) {
+        schedule_now(TaskType::BackfillStats);
     }
 
+    backfill_block_hashes();
+    migrate_stakes(now);
+
+    let next_round_ts = state.next_round_ts;
     replace_state(state);
-    setup_timer();
+    process_event(EventType::Upgrade {
+        config: read_config(|c| c.clone()),
+    });
+    checkpoint_state();
+    update_certified_data();
+    schedule_now(TaskType::MineBob);
+    schedule_now(TaskType::ResumeSpawns);
+    schedule_now(TaskType::MonitorMiners);
+    schedule_now(TaskType::IndexLeaderboard);
+    match next_round_ts {
+        Some(ts) => {
+            let delay_secs = ts.saturating_sub(now) / SEC_NANOS;
+            schedule_after(Duration::from_secs(delay_secs), TaskType::ProcessLogic);
+        }
+        None => schedule_round_end(Duration::from_secs(300)),
+    }
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    read_state(|s| save_state(s.clone()));
 }
 
 #[init]
-fn init() {
+fn init(minter_arg: Option<MinterArg>) {
+    let config = match minter_arg {
+        Some(MinterArg::Init(init_arg)) => Config::from_init_arg(init_arg),
+        Some(MinterArg::Upgrade(_)) => {
+            ic_cdk::trap("cannot install the minter with an upgrade argument")
+        }
+        None => Config::default(),
+    };
+    let pool_id = config.pool_id;
+    replace_config(config.clone());
+
     let state = State::new(ic_cdk::api::time());
 
-    let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
     insert_new_miner(pool_id, pool_id, 0);
 
     replace_state(state);
-    setup_timer();
+    process_event(EventType::Init { config });
+    process_event(EventType::MinerSpawned {
+        miner: pool_id,
+        owner: pool_id,
+        block_index: 0,
+    });
+    update_certified_data();
+    schedule_now(TaskType::MineBob);
+    schedule_now(TaskType::MonitorMiners);
+    schedule_round_end(Duration::from_secs(300));
 }
 
-fn setup_timer() {
-    schedule_now(TaskType::MineBob);
-    schedule_after(Duration::from_secs(300), TaskType::ProcessLogic);
+#[query]
+fn get_events(arg: GetEventsArg) -> GetEventsResult {
+    bob_minter_v2::event::get_events(arg)
+}
+
+#[query]
+fn get_config() -> Config {
+    read_config(|c| c.clone())
 }
 
 #[query]
@@ -76,17 +154,45 @@ fn filter_out_known_index(indices: Vec<u64>) -> Vec<u64> {
 
 #[query]
 fn get_latest_blocks() -> Vec<Block> {
-    let mut result: Vec<Block> = vec![];
-    let mut max_index = mined_block_count().checked_sub(1).unwrap();
-    while result.len() < 10 {
-        if let Some(block) = get_block(max_index) {
-            if block.miner.is_some() {
-                result.push(block);
-            }
-        }
-        max_index = max_index.checked_sub(1).unwrap();
-    }
-    result
+    (0..mined_block_count())
+        .rev()
+        .filter_map(get_block)
+        .filter(|block| block.miner.is_some())
+        .take(10)
+        .collect()
+}
+
+#[query]
+fn get_blocks(start: u64, length: u64, filter: Option<BlockFilter>) -> GetBlocksResponse {
+    bob_minter_v2::get_blocks(start, length, filter.unwrap_or_default())
+}
+
+#[query]
+fn icrc3_get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
+    bob_minter_v2::icrc3::get_blocks(args)
+}
+
+#[query]
+fn icrc3_get_tip_certificate() -> Option<ICRC3DataCertificate> {
+    tip_certificate()
+}
+
+#[query]
+fn icrc3_get_archives(_args: GetArchivesArgs) -> Vec<ICRC3ArchiveInfo> {
+    vec![]
+}
+
+#[query]
+fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    bob_minter_v2::icrc3::supported_block_types()
+}
+
+/// Returns what is needed to replay the winner selection of the block
+/// at the given index of the block log, see `transcript::verify_round`.
+#[query]
+fn get_round_transcript(block_index: u64) -> Option<RoundTranscript> {
+    get_block(block_index)
+        .and_then(|block| bob_minter_v2::memory::get_round_transcript(block.timestamp))
 }
 
 #[derive(CandidType)]
@@ -130,142 +236,351 @@ fn get_leader_board() -> Vec<LeaderBoardEntry> {
     result.iter().rev().take(20).cloned().collect()
 }
 
+/// Returns the last known status of the miner, checked by the minter
+/// about every hour.
+#[query]
+fn get_miner_health(miner: Principal) -> Option<MinerHealth> {
+    bob_minter_v2::memory::get_miner_health(miner)
+}
+
+/// Returns the miners with fewer cycles than the threshold, by default
+/// `LOW_CYCLES_THRESHOLD`, fewest cycles first. Miners that were never
+/// checked successfully are left out.
+#[query]
+fn get_low_cycles_miners(threshold: Option<u128>) -> Vec<MinerHealth> {
+    let threshold = threshold.unwrap_or(LOW_CYCLES_THRESHOLD);
+    let mut miners: Vec<MinerHealth> = get_all_miner_health()
+        .into_iter()
+        .filter(|health| health.is_low_on_cycles(threshold))
+        .collect();
+    miners.sort_by_key(|health| health.cycles);
+    miners
+}
+
+/// Returns the miners, pool membership and earnings of the owner.
+#[query]
+fn get_owner_overview(owner: Principal) -> OwnerOverview {
+    bob_minter_v2::overview::get_owner_overview(owner, ic_cdk::api::time())
+}
+
+/// Ranks the owners by the metric over the window, see `leaderboard`.
+#[query]
+fn get_leaderboard(arg: LeaderboardArg) -> LeaderboardPage {
+    bob_minter_v2::leaderboard::get_leaderboard(arg, ic_cdk::api::time())
+}
+
 #[update]
-async fn spawn_miner(block_index: u64) -> Result<Principal, String> {
-    // Transfer ICP to 6b896884e0b42634eca9c68c435c47b0ef2b97cf874a17198856b9c4efe89249
-    // With Memo 1347768404
-    if ic_cdk::caller() == Principal::anonymous() {
-        return Err("cannot spawn anonymously".to_string());
+async fn spawn_miner(block_index: u64) -> Result<Principal, SpawnMinerError> {
+    // Transfer ICP to one of the configured spawn payment accounts
+    // with the configured memo (by default 1347768404)
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(SpawnMinerError::AnonymousCaller);
     }
-    let _guard_principal = GuardPrincipal::new(ic_cdk::caller())
-        .map_err(|guard_error| format!("{:?}", guard_error))?;
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    let expected_to = read_config(|c| c.spawn_payment_accounts());
+    validate_payment(caller, block_index, &expected_to, MIN_PAYMENT_E8S)
+        .await
+        .map_err(|e| SpawnMinerError::from_payment_error(e, &expected_to))?;
+
+    start_spawn(caller, block_index).await
+}
+
+/// Spawns a miner paid with ICP the caller approved (ICRC-2) for the minter.
+#[update]
+async fn spawn_miner_with_approval() -> Result<Principal, SpawnMinerError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(SpawnMinerError::AnonymousCaller);
+    }
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    let block_index = pull_top_up_payment(caller, SPAWN_MINER_PRICE_E8S)
+        .await
+        .map_err(|error| SpawnMinerError::TransferFromFailed { error })?;
+    start_spawn(caller, block_index).await
+}
 
-    if read_state(|s| s.miner_block_index.contains(&block_index)) || is_known_block(block_index) {
-        return Err("already consumed block index".to_string());
+/// Continues the spawn paid by the ICP block `block_index` from the last
+/// step that succeeded.
+#[update]
+async fn resume_spawn(block_index: u64) -> Result<Principal, SpawnMinerError> {
+    let caller = ic_cdk::caller();
+    let request = get_spawn_request(block_index).ok_or(SpawnMinerError::UnknownSpawnRequest)?;
+    if request.owner != caller {
+        return Err(SpawnMinerError::WrongSender);
     }
+    let _guard_principal = GuardPrincipal::new(caller)?;
 
-    let transaction = fetch_block(block_index).await?.transaction;
+    advance_spawn(block_index).await
+}
 
-    if transaction.memo != icp_ledger::Memo(1347768404) {
-        return Err("unknown memo".to_string());
+#[query]
+fn get_spawn_request(block_index: u64) -> Option<SpawnRequest> {
+    bob_minter_v2::memory::get_spawn_request(block_index)
+}
+
+/// Resumes a spawn whose canister creation has an unknown outcome,
+/// controllers only. Pass the canister created for the spawn if there is
+/// one, or nothing to create a new one.
+#[update]
+fn recover_spawn(block_index: u64, miner: Option<Principal>) -> Result<SpawnRequest, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("only controllers can recover spawns".to_string());
     }
+    bob_minter_v2::spawn::recover_spawn(block_index, miner)
+}
 
-    let caller = AccountIdentifier::new(ic_types::PrincipalId(ic_cdk::caller()), None);
-    let expect_to = AccountIdentifier::from_hex(
-        "e7b583c3e3e2837c987831a97a6b980cbb0be89819e85915beb3c02006923fce",
-    )
-    .unwrap();
-    let old_to = AccountIdentifier::from_hex(
-        "6b896884e0b42634eca9c68c435c47b0ef2b97cf874a17198856b9c4efe89249",
-    )
-    .unwrap();
-
-    if let Operation::Transfer {
-        from, to, amount, ..
-    } = transaction.operation
-    {
-        assert_eq!(from, caller, "unexpected caller");
-        if to != expect_to && to != old_to {
-            panic!("unexpected destintaion");
-        }
-        assert!(
-            amount >= icp_ledger::Tokens::from_e8s(99_990_000_u64),
-            "unexpected amount"
-        );
-    } else {
-        return Err("expected transfer".to_string());
+#[update]
+async fn join_pool(block_index: u64) -> Result<(), JoinPoolError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(JoinPoolError::AnonymousCaller);
     }
+    let _guard_principal = GuardPrincipal::new(caller)?;
 
-    const CYCLES_FOR_CREATION: u64 = 2_500_000_000_000;
+    let expected_to = read_config(|c| c.pool_payment_account());
+    let amount_e8s = validate_payment(caller, block_index, &[expected_to], MIN_PAYMENT_E8S)
+        .await
+        .map_err(|e| JoinPoolError::from_payment_error(e, expected_to))?;
 
-    let _res = notify_top_up(block_index).await?;
+    add_pool_membership(caller, block_index, amount_e8s).await
+}
 
-    let arg = Encode!(&ic_cdk::caller()).unwrap();
+/// Joins the pool for `days` days, paid with ICP the caller approved
+/// (ICRC-2) for the minter.
+#[update]
+async fn join_pool_with_approval(days: u64) -> Result<(), JoinPoolError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(JoinPoolError::AnonymousCaller);
+    }
+    let amount_e8s = match days.checked_mul(E8S_PER_ICP) {
+        Some(amount_e8s) if days > 0 => amount_e8s,
+        _ => return Err(JoinPoolError::InvalidDays),
+    };
+    let _guard_principal = GuardPrincipal::new(caller)?;
 
-    let canister_id = create_canister(CYCLES_FOR_CREATION)
+    let block_index = pull_top_up_payment(caller, amount_e8s)
         .await
-        .map_err(|e| format!("{} - {:?}", e.method, e.reason))?;
+        .map_err(|error| JoinPoolError::TransferFromFailed { error })?;
+    add_pool_membership(caller, block_index, amount_e8s).await
+}
 
-    install_code(canister_id, miner_wasm().to_vec(), arg)
+/// Tops up the minter with the ICP of the payment and extends the pool
+/// membership of the owner by one day per ICP.
+async fn add_pool_membership(
+    owner: Principal,
+    block_index: u64,
+    amount_e8s: u64,
+) -> Result<(), JoinPoolError> {
+    let _res = notify_top_up(block_index, ic_cdk::id())
         .await
-        .map_err(|e| format!("{} - {:?}", e.method, e.reason))?;
+        .map_err(|e| JoinPoolError::CyclesTopUpFailed {
+            block_index,
+            error: e.to_string(),
+        })?;
 
-    mutate_state(|s| {
-        s.new_miner(canister_id, ic_cdk::caller(), block_index);
+    let now = ic_cdk::api::time();
+    let (from_time, stake) = match get_expiration(owner) {
+        Some(time) if time > now => (time, get_stake(owner).unwrap_or(0)),
+        _ => (now, 0),
+    };
+    let days = amount_e8s / E8S_PER_ICP;
+    let expire_at = from_time + days * DAY_NANOS;
+    insert_expiration(owner, expire_at);
+    insert_stake(owner, stake.saturating_add(amount_e8s));
+    process_event(EventType::PoolJoined {
+        owner,
+        block_index,
+        amount_e8s,
+        expiration: expire_at,
     });
+    insert_block_index(block_index);
+    update_certified_data();
+    Ok(())
+}
+
+#[update]
+async fn upgrade_miner(miner: Principal) -> Result<(), String> {
+    let owner = check_miner_owner(miner).map_err(|e| e.to_string())?;
+    let _guard_principal =
+        GuardPrincipal::new(owner).map_err(|guard_error| format!("{:?}", guard_error))?;
+    stop_canister(miner).await.map_err(|e| format!("{e:?}"))?;
+    upgrade_code(miner, miner_wasm().to_vec(), Encode!(&owner).unwrap())
+        .await
+        .map_err(|e| format!("{e:?}"))?;
+    start_canister(miner).await.map_err(|e| format!("{e:?}"))?;
+    Ok(())
+}
+
+/// Returns the owner of the miner if it is the caller.
+fn check_miner_owner(miner: Principal) -> Result<Principal, ManageMinerError> {
+    match get_miner_owner(miner) {
+        Some(owner) if owner == ic_cdk::caller() => Ok(owner),
+        Some(_) => Err(ManageMinerError::NotOwner),
+        None => Err(ManageMinerError::UnknownMiner),
+    }
+}
+
+/// Stops the miner until `resume_miner` is called.
+#[update]
+async fn pause_miner(miner: Principal) -> Result<(), ManageMinerError> {
+    let owner = check_miner_owner(miner)?;
+    let _guard_principal = GuardPrincipal::new(owner)?;
+    stop_canister(miner).await.map_err(ManageMinerError::from)
+}
 
-    insert_new_miner(canister_id, ic_cdk::caller(), block_index);
+#[update]
+async fn resume_miner(miner: Principal) -> Result<(), ManageMinerError> {
+    let owner = check_miner_owner(miner)?;
+    let _guard_principal = GuardPrincipal::new(owner)?;
+    start_canister(miner).await.map_err(ManageMinerError::from)
+}
+
+/// Deletes the miner. Unless `cycles_to` is empty, the remaining cycles of
+/// the miner are first deposited to that canister. Returns the deposited
+/// cycles. If a previous call withdrew the cycles but failed to delete the
+/// miner, the deletion is retried without withdrawing again.
+#[update]
+async fn decommission_miner(
+    miner: Principal,
+    cycles_to: Option<Principal>,
+) -> Result<u128, ManageMinerError> {
+    let owner = check_miner_owner(miner)?;
+    let _guard_principal = GuardPrincipal::new(owner)?;
+
+    let withdrawal = read_state(|s| s.miner_to_withdrawal.get(&miner).copied());
+    let (withdrawn_cycles, cycles_to) = match (withdrawal, cycles_to) {
+        (Some((cycles, to)), _) => (cycles, Some(to)),
+        (None, Some(to)) => {
+            // a paused miner cannot answer
+            start_canister(miner).await?;
+            let cycles = withdraw_cycles(miner, to).await?;
+            process_event(EventType::MinerCyclesWithdrawn {
+                miner,
+                owner,
+                cycles,
+                cycles_to: to,
+            });
+            disable_auto_top_up(miner, "the miner was decommissioned");
+            (cycles, Some(to))
+        }
+        (None, None) => (0, None),
+    };
+    stop_canister(miner).await?;
+    delete_canister(miner).await?;
+
+    process_event(EventType::MinerDecommissioned {
+        miner,
+        owner,
+        withdrawn_cycles,
+        cycles_to,
+    });
+    remove_miner(miner);
+    disable_auto_top_up(miner, "the miner was decommissioned");
 
-    Ok(canister_id)
+    Ok(withdrawn_cycles)
 }
 
+/// Converts the ICP payment at `block_index` to cycles of the miner. The
+/// payment must be sent by the caller to the top-up account of the miner
+/// at the cycles minting canister, with the top-up memo.
 #[update]
-async fn join_pool(block_index: u64) -> Result<(), String> {
-    if ic_cdk::caller() == Principal::anonymous() {
-        return Err("cannot spawn anonymously".to_string());
+async fn top_up_miner(miner: Principal, block_index: u64) -> Result<MinerTopUp, TopUpMinerError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(TopUpMinerError::AnonymousCaller);
     }
-    let _guard_principal = GuardPrincipal::new(ic_cdk::caller())
-        .map_err(|guard_error| format!("{:?}", guard_error))?;
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    bob_minter_v2::top_up::top_up_miner(caller, miner, block_index).await
+}
 
-    if read_state(|s| s.miner_block_index.contains(&block_index)) || is_known_block(block_index) {
-        return Err("already consumed block index".to_string());
+/// Returns the top-ups of the miner, oldest payment first.
+#[query]
+fn get_miner_top_ups(miner: Principal) -> Vec<MinerTopUp> {
+    get_top_ups_of(miner)
+}
+
+/// Deposits ICP the caller approved (ICRC-2) for the minter, used to pay
+/// the auto top-ups of its miners. Returns the new balance of the deposit.
+#[update]
+async fn deposit_icp(amount_e8s: u64) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("the anonymous principal cannot deposit".to_string());
     }
+    let _guard_principal =
+        GuardPrincipal::new(caller).map_err(|guard_error| format!("{:?}", guard_error))?;
 
-    let transaction = fetch_block(block_index).await?.transaction;
+    bob_minter_v2::auto_top_up::deposit_icp(caller, amount_e8s).await
+}
 
-    if transaction.memo != icp_ledger::Memo(1347768404) {
-        return Err("unknown memo".to_string());
+/// Sends `amount_e8s` of the deposit of the caller, minus the transfer fee,
+/// to its ICP account. Returns the new balance of the deposit.
+#[update]
+async fn withdraw_deposit(amount_e8s: u64) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("the anonymous principal has no deposit".to_string());
     }
+    let _guard_principal =
+        GuardPrincipal::new(caller).map_err(|guard_error| format!("{:?}", guard_error))?;
 
-    let caller = AccountIdentifier::new(ic_types::PrincipalId(ic_cdk::caller()), None);
-    let expect_to = AccountIdentifier::from_hex(
-        "e7b583c3e3e2837c987831a97a6b980cbb0be89819e85915beb3c02006923fce",
-    )
-    .unwrap();
-
-    if let Operation::Transfer {
-        from, to, amount, ..
-    } = transaction.operation
-    {
-        assert_eq!(from, caller, "unexpected caller");
-        if to != expect_to {
-            panic!("unexpected destintaion");
-        }
-        assert!(
-            amount >= icp_ledger::Tokens::from_e8s(99_990_000_u64),
-            "amount too low"
-        );
+    bob_minter_v2::auto_top_up::withdraw_deposit(caller, amount_e8s).await
+}
 
-        let _res = notify_top_up(block_index).await?;
+/// Returns the ICP deposit (in e8s) of the given principal (or the caller).
+#[query]
+fn get_deposit(maybe_target: Option<Principal>) -> u64 {
+    bob_minter_v2::memory::get_deposit(maybe_target.unwrap_or(ic_cdk::caller()))
+}
 
-        let caller = ic_cdk::caller();
-        let from_time = if let Some(time) = get_expiration(caller) {
-            time
-        } else {
-            ic_cdk::api::time()
-        };
-        let days = amount.get_e8s() / 100_000_000;
-        let expire_at = from_time + days * DAY_NANOS;
-        insert_expiration(caller, expire_at);
-        insert_block_index(block_index);
-        Ok(())
-    } else {
-        Err("expected transfer".to_string())
-    }
+/// Tops up the miner from the deposit of the owner whenever the minter
+/// sees it below the threshold of the policy. Without a policy, auto
+/// top-ups of the miner are disabled.
+#[update]
+fn set_auto_top_up(miner: Principal, policy: Option<AutoTopUpPolicy>) -> Result<(), String> {
+    let owner = check_miner_owner(miner).map_err(|e| e.to_string())?;
+    let _guard_principal =
+        GuardPrincipal::new(owner).map_err(|guard_error| format!("{:?}", guard_error))?;
+
+    bob_minter_v2::auto_top_up::set_auto_top_up(miner, owner, policy)
+}
+
+/// Returns the auto top-ups of the miners of the given principal (or the
+/// caller).
+#[query]
+fn get_auto_top_ups(maybe_target: Option<Principal>) -> Vec<AutoTopUp> {
+    let target = maybe_target.unwrap_or(ic_cdk::caller());
+    bob_minter_v2::memory::get_auto_top_ups()
+        .into_iter()
+        .filter(|auto_top_up| auto_top_up.owner == target)
+        .collect()
 }
 
+/// Transfers the miner to `new_owner`, in the miner canister and in the
+/// registry of the minter. The new owner receives the future rewards.
 #[update]
-async fn upgrade_miner(miner: Principal) -> Result<(), String> {
-    if let Some(owner) = get_miner_owner(miner) {
-        assert_eq!(ic_cdk::caller(), owner);
-        stop_canister(miner).await.map_err(|e| format!("{e:?}"))?;
-        reinstall_code(miner, miner_wasm().to_vec(), Encode!(&owner).unwrap())
-            .await
-            .map_err(|e| format!("{e:?}"))?;
-        start_canister(miner).await.map_err(|e| format!("{e:?}"))?;
-        return Ok(());
+async fn transfer_miner(miner: Principal, new_owner: Principal) -> Result<(), ManageMinerError> {
+    if new_owner == Principal::anonymous() {
+        return Err(ManageMinerError::AnonymousNewOwner);
     }
-    Err("unknown miner".to_string())
+    let owner = check_miner_owner(miner)?;
+    let _guard_principal = GuardPrincipal::new(owner)?;
+
+    set_owner(miner, new_owner).await?;
+
+    process_event(EventType::MinerTransferred {
+        miner,
+        from: owner,
+        to: new_owner,
+    });
+    set_miner_owner(miner, new_owner);
+
+    Ok(())
 }
 
 #[export_name = "canister_global_timer"]
@@ -289,44 +604,64 @@ fn submit_burned_cycles(cycles: u64) -> Result<(), String> {
         return Err("Not enough cycle burned".to_string());
     }
 
-    let caller = ic_cdk::caller();
-
-    mutate_state(|s| {
-        s.miner_to_burned_cycles
-            .entry(caller)
-            .and_modify(|e| *e += cycles)
-            .or_insert(cycles);
-    });
+    let now = ic_cdk::api::time();
+    mutate_state(|s| s.submit_cycles(ic_cdk::caller(), cycles, now));
 
     Ok(())
 }
 
 #[query]
 fn get_statistics() -> Stats {
+    let now = ic_cdk::api::time();
     read_state(|s| Stats {
-        average_block_speed: 0,
+        average_block_speed: s
+            .block_times
+            .stats(BlockWindow::LastBlocks(100), now)
+            .mean_secs,
         block_count: s.total_blocks_mined(),
         miner_count: s.miner_to_owner.keys().len(),
         halving_count: s.total_blocks_mined() / BLOCK_HALVING,
         cycle_balance: ic_cdk::api::canister_balance(),
         time_since_last_block: s.time_since_last_block(),
         pending_blocks: get_block_to_mine(),
+        outstanding_payouts: outstanding_payout_count(),
+    })
+}
+
+#[query]
+fn get_block_speed_stats(windows: Option<Vec<BlockWindow>>) -> Vec<BlockSpeedStats> {
+    let now = ic_cdk::api::time();
+    let windows = windows.unwrap_or_else(|| DEFAULT_WINDOWS.to_vec());
+    read_state(|s| {
+        windows
+            .into_iter()
+            .map(|window| s.block_times.stats(window, now))
+            .collect()
     })
 }
 
+#[query]
+fn get_certified_statistics() -> CertifiedStats {
+    certified_stats()
+}
+
 #[derive(CandidType)]
 struct PoolStats {
     pool_mined_blocks: u64,
     users_count_in_pool: u64,
+    total_stake_e8s: u64,
+    reward_remainder: u64,
 }
 
 #[query]
 fn get_pool_statistic() -> PoolStats {
-    let pool_id = Principal::from_text("zje3u-qaaaa-aaaai-acr2a-cai").unwrap();
+    let pool_id = read_config(|c| c.pool_id);
 
     read_state(|s| PoolStats {
         pool_mined_blocks: *s.miner_to_mined_block.get(&pool_id).unwrap_or(&0),
         users_count_in_pool: user_count(),
+        total_stake_e8s: total_stake(ic_cdk::api::time()),
+        reward_remainder: get_pool_reward_remainder(),
     })
 }
 
@@ -338,6 +673,52 @@ fn hours_left_in_pool(maybe_target: Option<Principal>) -> u64 {
     expiration.saturating_sub(now) / (60 * 60 * SEC_NANOS)
 }
 
+/// Sends the ICP the caller transferred to its deposit account at
+/// `block_index` back to the caller, if it was not deposited with
+/// `deposit_icp`.
+#[update]
+async fn request_refund(block_index: u64) -> Result<Refund, RefundError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(RefundError::AnonymousCaller);
+    }
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    bob_minter_v2::refund::request_refund(caller, block_index).await
+}
+
+/// Returns the refunds of the given principal (or the caller).
+#[query]
+fn get_refunds(maybe_target: Option<Principal>) -> Vec<Refund> {
+    get_refunds_of(maybe_target.unwrap_or(ic_cdk::caller()))
+}
+
+/// Returns the rewards of the given principal (or the caller) that have
+/// not been transferred yet.
+#[query]
+fn get_outstanding_payouts(maybe_target: Option<Principal>) -> Vec<Payout> {
+    let target = maybe_target.unwrap_or(ic_cdk::caller());
+    get_payouts_of(target)
+        .into_iter()
+        .filter(|payout| !payout.is_paid())
+        .collect()
+}
+
+/// Resolves a payout that needs reconciliation, controllers only. Pass the
+/// index of the ledger transfer if the payout went through, or nothing to
+/// send it again.
+#[update]
+fn reconcile_payout(
+    recipient: Principal,
+    block_index: u64,
+    ledger_index: Option<u64>,
+) -> Result<Payout, String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err("only controllers can reconcile payouts".to_string());
+    }
+    bob_minter_v2::payouts::reconcile_payout(recipient, block_index, ledger_index)
+}
+
 #[derive(CandidType)]
 struct Miner {
     pub id: Principal,
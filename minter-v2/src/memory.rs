@@ -1,11 +1,25 @@
-use crate::Block;
+use crate::auto_top_up::{AutoTopUp, PendingTransfer};
+use crate::config::Config;
+use crate::event::{Checkpoint, Event};
+use crate::health::MinerHealth;
+use crate::icrc3::{block_hash, Hash};
+use crate::leaderboard::OwnerActivity;
+use crate::payouts::Payout;
+use crate::refund::Refund;
+use crate::spawn::SpawnRequest;
+use crate::top_up::MinerTopUp;
+use crate::transcript::RoundTranscript;
+use crate::{Block, State, VersionedState, DAY_NANOS, E8S_PER_ICP};
 use candid::Principal;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager as MM, VirtualMemory};
 use ic_stable_structures::storable::Bound;
 use ic_stable_structures::DefaultMemoryImpl;
-use ic_stable_structures::{DefaultMemoryImpl as DefMem, StableBTreeMap, StableLog, Storable};
+use ic_stable_structures::{
+    DefaultMemoryImpl as DefMem, StableBTreeMap, StableCell, StableLog, StableVec, Storable,
+};
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::ops;
 
 #[derive(Default, Ord, PartialOrd, Clone, Eq, PartialEq)]
 struct Cbor<T>(pub T)
@@ -37,6 +51,27 @@ const LOG_DATA_MEM_ID: MemoryId = MemoryId::new(2);
 const BLOCKS_TO_MINE_ID: MemoryId = MemoryId::new(3);
 const USER_TO_EXPIRATION_ID: MemoryId = MemoryId::new(4);
 const KNOWN_BLOCK_INDEX_ID: MemoryId = MemoryId::new(5);
+const BLOCK_HASHES_ID: MemoryId = MemoryId::new(6);
+const ROUND_TRANSCRIPTS_ID: MemoryId = MemoryId::new(7);
+const PAYOUTS_ID: MemoryId = MemoryId::new(8);
+const OUTSTANDING_PAYOUTS_ID: MemoryId = MemoryId::new(9);
+const USER_TO_STAKE_ID: MemoryId = MemoryId::new(10);
+const POOL_REWARD_REMAINDER_ID: MemoryId = MemoryId::new(11);
+const CONFIG_ID: MemoryId = MemoryId::new(12);
+const STATE_ID: MemoryId = MemoryId::new(13);
+const EVENT_LOG_INDX_MEM_ID: MemoryId = MemoryId::new(14);
+const EVENT_LOG_DATA_MEM_ID: MemoryId = MemoryId::new(15);
+const SPAWN_REQUESTS_ID: MemoryId = MemoryId::new(16);
+const PENDING_SPAWNS_ID: MemoryId = MemoryId::new(17);
+const REFUNDS_ID: MemoryId = MemoryId::new(18);
+const OWNER_ACTIVITY_ID: MemoryId = MemoryId::new(19);
+const LEADERBOARD_INDEXED_ID: MemoryId = MemoryId::new(20);
+const MINER_HEALTH_ID: MemoryId = MemoryId::new(21);
+const TOP_UPS_ID: MemoryId = MemoryId::new(22);
+const DEPOSITS_ID: MemoryId = MemoryId::new(23);
+const AUTO_TOP_UPS_ID: MemoryId = MemoryId::new(24);
+const PENDING_WITHDRAWALS_ID: MemoryId = MemoryId::new(25);
+const CHECKPOINT_ID: MemoryId = MemoryId::new(26);
 
 type VM = VirtualMemory<DefMem>;
 
@@ -72,6 +107,122 @@ thread_local! {
         MEMORY_MANAGER.with(|mm| {
         RefCell::new(StableBTreeMap::init(mm.borrow().get(KNOWN_BLOCK_INDEX_ID)))
         });
+
+    static BLOCK_HASHES: RefCell<StableVec<Hash, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableVec::init(mm.borrow().get(BLOCK_HASHES_ID))
+            .expect("failed to initialize the block hashes"))
+        });
+
+    static ROUND_TRANSCRIPTS: RefCell<StableBTreeMap<u64, Cbor<RoundTranscript>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(ROUND_TRANSCRIPTS_ID)))
+        });
+
+    static PAYOUTS: RefCell<StableBTreeMap<(Principal, u64), Cbor<Payout>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PAYOUTS_ID)))
+        });
+
+    static OUTSTANDING_PAYOUTS: RefCell<StableBTreeMap<(u64, Principal), (), VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(OUTSTANDING_PAYOUTS_ID)))
+        });
+
+    /// ICP (in e8s) paid by each pool member for the current membership.
+    static USER_TO_STAKE: RefCell<StableBTreeMap<Principal, u64, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(USER_TO_STAKE_ID)))
+        });
+
+    /// BoB of pool blocks that could not be split evenly yet.
+    static POOL_REWARD_REMAINDER: RefCell<StableCell<u64, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(mm.borrow().get(POOL_REWARD_REMAINDER_ID), 0)
+            .expect("failed to initialize the pool reward remainder"))
+        });
+
+    static CONFIG: RefCell<StableCell<Cbor<Config>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(mm.borrow().get(CONFIG_ID), Cbor::default())
+            .expect("failed to initialize the config"))
+        });
+
+    static STATE: RefCell<StableCell<Cbor<Option<VersionedState>>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(mm.borrow().get(STATE_ID), Cbor(None))
+            .expect("failed to initialize the state memory"))
+        });
+
+    static EVENT_LOG: RefCell<StableLog<Cbor<Event>, VM, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableLog::init(
+            mm.borrow().get(EVENT_LOG_INDX_MEM_ID),
+            mm.borrow().get(EVENT_LOG_DATA_MEM_ID),
+        ).expect("failed to initialize the event log"))
+        });
+
+    static SPAWN_REQUESTS: RefCell<StableBTreeMap<u64, Cbor<SpawnRequest>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(SPAWN_REQUESTS_ID)))
+        });
+
+    static PENDING_SPAWNS: RefCell<StableBTreeMap<u64, (), VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PENDING_SPAWNS_ID)))
+        });
+
+    static REFUNDS: RefCell<StableBTreeMap<(Principal, u64), Cbor<Refund>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(REFUNDS_ID)))
+        });
+
+    /// Activity of each owner per hour, see `leaderboard::index_blocks`.
+    static OWNER_ACTIVITY: RefCell<StableBTreeMap<(u64, Principal), Cbor<OwnerActivity>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(OWNER_ACTIVITY_ID)))
+        });
+
+    /// Number of blocks of the log included in `OWNER_ACTIVITY`.
+    static LEADERBOARD_INDEXED: RefCell<StableCell<u64, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(mm.borrow().get(LEADERBOARD_INDEXED_ID), 0)
+            .expect("failed to initialize the leaderboard index"))
+        });
+
+    /// Last `canister_status` of each miner, see `health::monitor_miners`.
+    static MINER_HEALTH: RefCell<StableBTreeMap<Principal, Cbor<MinerHealth>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(MINER_HEALTH_ID)))
+        });
+
+    static TOP_UPS: RefCell<StableBTreeMap<(Principal, u64), Cbor<MinerTopUp>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(TOP_UPS_ID)))
+        });
+
+    /// ICP (in e8s) deposited by each owner for auto top-ups.
+    static DEPOSITS: RefCell<StableBTreeMap<Principal, u64, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(DEPOSITS_ID)))
+        });
+
+    static AUTO_TOP_UPS: RefCell<StableBTreeMap<Principal, Cbor<AutoTopUp>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(AUTO_TOP_UPS_ID)))
+        });
+
+    /// Withdrawal of each owner whose outcome is not known yet.
+    static PENDING_WITHDRAWALS: RefCell<StableBTreeMap<Principal, Cbor<PendingTransfer>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableBTreeMap::init(mm.borrow().get(PENDING_WITHDRAWALS_ID)))
+        });
+
+    static CHECKPOINT: RefCell<StableCell<Cbor<Option<Checkpoint>>, VM>> =
+        MEMORY_MANAGER.with(|mm| {
+        RefCell::new(StableCell::init(mm.borrow().get(CHECKPOINT_ID), Cbor(None))
+            .expect("failed to initialize the checkpoint memory"))
+        });
 }
 
 pub fn insert_block_to_mine(block: Block) {
@@ -90,10 +241,37 @@ pub fn should_mine() -> bool {
     BLOCKS_TO_MINE.with(|s| s.borrow().len()) > 0
 }
 
-pub fn push_block(block: Block) {
-    TX_LOG
+pub fn push_block(block: Block) -> u64 {
+    let parent_hash = mined_block_count()
+        .checked_sub(1)
+        .map(|parent_index| get_block_hash(parent_index).expect("missing parent hash"));
+    let hash = block_hash(&block, parent_hash);
+    let index = TX_LOG
         .with(|s| s.borrow().append(&Cbor(block)))
         .expect("failed to push block");
+    BLOCK_HASHES
+        .with(|s| s.borrow().push(&hash))
+        .expect("failed to push block hash");
+    index
+}
+
+pub fn get_block_hash(index: u64) -> Option<Hash> {
+    BLOCK_HASHES.with(|s| s.borrow().get(index))
+}
+
+/// Computes the missing hashes of blocks appended before the hash
+/// chain was stored next to the block log.
+pub fn backfill_block_hashes() {
+    let hashed_count = BLOCK_HASHES.with(|s| s.borrow().len());
+    let mut parent_hash = hashed_count.checked_sub(1).and_then(get_block_hash);
+    for index in hashed_count..mined_block_count() {
+        let block = get_block(index).expect("missing block");
+        let hash = block_hash(&block, parent_hash);
+        BLOCK_HASHES
+            .with(|s| s.borrow().push(&hash))
+            .expect("failed to push block hash");
+        parent_hash = Some(hash);
+    }
 }
 
 pub fn get_block(index: u64) -> Option<Block> {
@@ -112,10 +290,46 @@ pub fn insert_new_miner(miner: Principal, owner: Principal, block_index: u64) {
     MINER_TO_OWNER.with(|s| s.borrow_mut().insert(miner, (owner, block_index)));
 }
 
+/// Changes the owner of the miner, keeping the index of its payment.
+pub fn set_miner_owner(miner: Principal, owner: Principal) {
+    MINER_TO_OWNER.with(|s| {
+        let mut map = s.borrow_mut();
+        if let Some((_, block_index)) = map.get(&miner) {
+            map.insert(miner, (owner, block_index));
+        }
+    });
+}
+
+pub fn remove_miner(miner: Principal) {
+    MINER_TO_OWNER.with(|s| s.borrow_mut().remove(&miner));
+    MINER_HEALTH.with(|s| s.borrow_mut().remove(&miner));
+}
+
 pub fn get_miner_owner(miner: Principal) -> Option<Principal> {
     MINER_TO_OWNER.with(|s| s.borrow().get(&miner).map(|(owner, _)| owner))
 }
 
+/// Index of the ICP block that paid for the miner.
+pub fn get_miner_block_index(miner: Principal) -> Option<u64> {
+    MINER_TO_OWNER.with(|s| s.borrow().get(&miner).map(|(_, block_index)| block_index))
+}
+
+/// Returns up to `limit` registered miners following `after`, in
+/// principal order.
+pub fn get_miners_after(after: Option<Principal>, limit: usize) -> Vec<Principal> {
+    MINER_TO_OWNER.with(|s| {
+        let map = s.borrow();
+        match after {
+            Some(after) => map
+                .range((ops::Bound::Excluded(after), ops::Bound::Unbounded))
+                .map(|(miner, _)| miner)
+                .take(limit)
+                .collect(),
+            None => map.iter().map(|(miner, _)| miner).take(limit).collect(),
+        }
+    })
+}
+
 pub fn miner_count() -> u64 {
     MINER_TO_OWNER.with(|s| s.borrow().len())
 }
@@ -156,10 +370,58 @@ pub fn remove_expired_entries(current_time: u64) {
 
         for key in keys_to_remove {
             map.remove(&key);
+            USER_TO_STAKE.with(|s| s.borrow_mut().remove(&key));
         }
     });
 }
 
+pub fn insert_stake(owner: Principal, stake_e8s: u64) {
+    USER_TO_STAKE.with(|s| s.borrow_mut().insert(owner, stake_e8s));
+}
+
+pub fn get_stake(owner: Principal) -> Option<u64> {
+    USER_TO_STAKE.with(|s| s.borrow().get(&owner))
+}
+
+/// Stakes of all current pool members. Members without a recorded
+/// stake are left out.
+pub fn get_stake_map() -> Vec<(Principal, u64)> {
+    get_expire_map()
+        .into_iter()
+        .filter_map(|(owner, _)| get_stake(owner).map(|stake| (owner, stake)))
+        .collect()
+}
+
+/// Total stake of the members whose membership has not expired at `now`.
+pub fn total_stake(now: u64) -> u64 {
+    get_expire_map()
+        .into_iter()
+        .filter(|(_, expiration)| *expiration > now)
+        .filter_map(|(owner, _)| get_stake(owner))
+        .fold(0_u64, |total, stake| total.saturating_add(stake))
+}
+
+/// Gives pool members who joined before stakes were recorded a stake
+/// of one ICP per remaining day of membership.
+pub fn migrate_stakes(now: u64) {
+    for (owner, expiration) in get_expire_map() {
+        if get_stake(owner).is_none() {
+            let remaining_days = expiration.saturating_sub(now).div_ceil(DAY_NANOS);
+            insert_stake(owner, remaining_days * E8S_PER_ICP);
+        }
+    }
+}
+
+pub fn get_pool_reward_remainder() -> u64 {
+    POOL_REWARD_REMAINDER.with(|s| *s.borrow().get())
+}
+
+pub fn set_pool_reward_remainder(remainder: u64) {
+    POOL_REWARD_REMAINDER
+        .with(|s| s.borrow_mut().set(remainder))
+        .expect("failed to set the pool reward remainder");
+}
+
 pub fn is_known_block(block_index: u64) -> bool {
     KNOWN_INDEX.with(|s| s.borrow().get(&block_index).is_some())
 }
@@ -167,3 +429,278 @@ pub fn is_known_block(block_index: u64) -> bool {
 pub fn insert_block_index(block_index: u64) {
     KNOWN_INDEX.with(|s| s.borrow_mut().insert(block_index, ()));
 }
+
+pub fn insert_round_transcript(block_timestamp: u64, transcript: RoundTranscript) {
+    ROUND_TRANSCRIPTS.with(|s| s.borrow_mut().insert(block_timestamp, Cbor(transcript)));
+}
+
+pub fn get_round_transcript(block_timestamp: u64) -> Option<RoundTranscript> {
+    ROUND_TRANSCRIPTS.with(|s| s.borrow().get(&block_timestamp).map(|t| t.0))
+}
+
+/// Up to `limit` round transcripts in the range, keyed by the timestamp of
+/// their block.
+pub fn get_round_transcripts(range: ops::Range<u64>, limit: u64) -> Vec<(u64, RoundTranscript)> {
+    ROUND_TRANSCRIPTS.with(|s| {
+        s.borrow()
+            .range(range)
+            .take(limit as usize)
+            .map(|(ts, t)| (ts, t.0))
+            .collect()
+    })
+}
+
+pub fn insert_payout(payout: Payout) {
+    let recipient = payout.recipient;
+    let block_index = payout.block_index;
+    OUTSTANDING_PAYOUTS.with(|s| {
+        if !payout.is_outstanding() {
+            s.borrow_mut().remove(&(block_index, recipient));
+        } else {
+            s.borrow_mut().insert((block_index, recipient), ());
+        }
+    });
+    PAYOUTS.with(|s| {
+        s.borrow_mut()
+            .insert((recipient, block_index), Cbor(payout))
+    });
+}
+
+pub fn get_payout(recipient: Principal, block_index: u64) -> Option<Payout> {
+    PAYOUTS.with(|s| s.borrow().get(&(recipient, block_index)).map(|p| p.0))
+}
+
+pub fn get_payouts_of(recipient: Principal) -> Vec<Payout> {
+    PAYOUTS.with(|s| {
+        s.borrow()
+            .range((recipient, 0)..=(recipient, u64::MAX))
+            .map(|(_, p)| p.0)
+            .collect()
+    })
+}
+
+/// Returns the (block index, recipient) pairs of all payouts that have not
+/// been transferred yet, oldest block first.
+pub fn get_outstanding_payouts() -> Vec<(u64, Principal)> {
+    OUTSTANDING_PAYOUTS.with(|s| s.borrow().iter().map(|(key, _)| key).collect())
+}
+
+/// The outstanding payouts to `recipient`.
+pub fn get_outstanding_payouts_of(recipient: Principal) -> Vec<Payout> {
+    get_outstanding_payouts()
+        .into_iter()
+        .filter(|(_, to)| *to == recipient)
+        .filter_map(|(block_index, to)| get_payout(to, block_index))
+        .collect()
+}
+
+pub fn outstanding_payout_count() -> u64 {
+    OUTSTANDING_PAYOUTS.with(|s| s.borrow().len())
+}
+
+pub fn get_config() -> Config {
+    CONFIG.with(|s| s.borrow().get().0.clone())
+}
+
+pub fn set_config(config: Config) {
+    CONFIG
+        .with(|s| s.borrow_mut().set(Cbor(config)))
+        .expect("failed to set the config");
+}
+
+/// Writes the state to stable memory, see `take_state`.
+pub fn save_state(state: State) {
+    STATE
+        .with(|s| s.borrow_mut().set(Cbor(Some(VersionedState::V1(state)))))
+        .expect("failed to save the state");
+}
+
+/// Returns the state saved before the upgrade and clears it, so that a
+/// stale copy is never restored by a later upgrade.
+pub fn take_state() -> Option<State> {
+    STATE.with(|s| {
+        let mut cell = s.borrow_mut();
+        let state = cell.get().0.clone().map(VersionedState::into_state);
+        cell.set(Cbor(None))
+            .expect("failed to clear the saved state");
+        state
+    })
+}
+
+pub fn append_event(event: &Event) {
+    EVENT_LOG
+        .with(|s| s.borrow().append(&Cbor(event.clone())))
+        .expect("failed to append an event");
+}
+
+pub fn event_count() -> u64 {
+    EVENT_LOG.with(|s| s.borrow().len())
+}
+
+pub fn get_events(start: u64, length: u64) -> Vec<Event> {
+    EVENT_LOG.with(|s| {
+        let log = s.borrow();
+        (start..start.saturating_add(length).min(log.len()))
+            .filter_map(|index| log.get(index).map(|e| e.0))
+            .collect()
+    })
+}
+
+pub fn save_checkpoint(checkpoint: Checkpoint) {
+    CHECKPOINT
+        .with(|s| s.borrow_mut().set(Cbor(Some(checkpoint))))
+        .expect("failed to save the checkpoint");
+}
+
+pub fn get_checkpoint() -> Option<Checkpoint> {
+    CHECKPOINT.with(|s| s.borrow().get().0.clone())
+}
+
+pub fn insert_spawn_request(request: SpawnRequest) {
+    let block_index = request.block_index;
+    PENDING_SPAWNS.with(|s| {
+        if request.is_final() {
+            s.borrow_mut().remove(&block_index);
+        } else {
+            s.borrow_mut().insert(block_index, ());
+        }
+    });
+    SPAWN_REQUESTS.with(|s| s.borrow_mut().insert(block_index, Cbor(request)));
+}
+
+pub fn get_spawn_request(block_index: u64) -> Option<SpawnRequest> {
+    SPAWN_REQUESTS.with(|s| s.borrow().get(&block_index).map(|r| r.0))
+}
+
+/// Returns the block indices of the spawn requests that did not reach a
+/// final step yet.
+pub fn get_pending_spawns() -> Vec<u64> {
+    PENDING_SPAWNS.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(block_index, _)| block_index)
+            .collect()
+    })
+}
+
+pub fn insert_refund(refund: Refund) {
+    REFUNDS.with(|s| {
+        s.borrow_mut()
+            .insert((refund.recipient, refund.block_index), Cbor(refund))
+    });
+}
+
+pub fn get_refund(recipient: Principal, block_index: u64) -> Option<Refund> {
+    REFUNDS.with(|s| s.borrow().get(&(recipient, block_index)).map(|r| r.0))
+}
+
+pub fn get_refunds_of(recipient: Principal) -> Vec<Refund> {
+    REFUNDS.with(|s| {
+        s.borrow()
+            .range((recipient, 0)..=(recipient, u64::MAX))
+            .map(|(_, r)| r.0)
+            .collect()
+    })
+}
+
+pub fn insert_owner_activity(hour: u64, owner: Principal, activity: OwnerActivity) {
+    OWNER_ACTIVITY.with(|s| s.borrow_mut().insert((hour, owner), Cbor(activity)));
+}
+
+pub fn get_owner_activity(hour: u64, owner: Principal) -> Option<OwnerActivity> {
+    OWNER_ACTIVITY.with(|s| s.borrow().get(&(hour, owner)).map(|a| a.0))
+}
+
+/// Returns the activities of all owners from `first_hour` to `last_hour`
+/// included.
+pub fn get_activities(first_hour: u64, last_hour: u64) -> Vec<((u64, Principal), OwnerActivity)> {
+    OWNER_ACTIVITY.with(|s| {
+        s.borrow()
+            .range((first_hour, Principal::management_canister())..)
+            .take_while(|((hour, _), _)| *hour <= last_hour)
+            .map(|(key, a)| (key, a.0))
+            .collect()
+    })
+}
+
+pub fn get_leaderboard_indexed_count() -> u64 {
+    LEADERBOARD_INDEXED.with(|s| *s.borrow().get())
+}
+
+pub fn set_leaderboard_indexed_count(count: u64) {
+    LEADERBOARD_INDEXED
+        .with(|s| s.borrow_mut().set(count))
+        .expect("failed to set the leaderboard index");
+}
+
+pub fn insert_miner_health(health: MinerHealth) {
+    MINER_HEALTH.with(|s| s.borrow_mut().insert(health.miner, Cbor(health)));
+}
+
+pub fn get_miner_health(miner: Principal) -> Option<MinerHealth> {
+    MINER_HEALTH.with(|s| s.borrow().get(&miner).map(|h| h.0))
+}
+
+pub fn get_all_miner_health() -> Vec<MinerHealth> {
+    MINER_HEALTH.with(|s| s.borrow().iter().map(|(_, h)| h.0).collect())
+}
+
+pub fn insert_top_up(top_up: MinerTopUp) {
+    TOP_UPS.with(|s| {
+        s.borrow_mut()
+            .insert((top_up.miner, top_up.block_index), Cbor(top_up))
+    });
+}
+
+pub fn get_top_up(miner: Principal, block_index: u64) -> Option<MinerTopUp> {
+    TOP_UPS.with(|s| s.borrow().get(&(miner, block_index)).map(|t| t.0))
+}
+
+pub fn get_top_ups_of(miner: Principal) -> Vec<MinerTopUp> {
+    TOP_UPS.with(|s| {
+        s.borrow()
+            .range((miner, 0)..=(miner, u64::MAX))
+            .map(|(_, t)| t.0)
+            .collect()
+    })
+}
+
+pub fn get_deposit(owner: Principal) -> u64 {
+    DEPOSITS.with(|s| s.borrow().get(&owner).unwrap_or(0))
+}
+
+pub fn set_deposit(owner: Principal, amount_e8s: u64) {
+    DEPOSITS.with(|s| s.borrow_mut().insert(owner, amount_e8s));
+}
+
+pub fn insert_auto_top_up(auto_top_up: AutoTopUp) {
+    AUTO_TOP_UPS.with(|s| s.borrow_mut().insert(auto_top_up.miner, Cbor(auto_top_up)));
+}
+
+pub fn get_auto_top_up(miner: Principal) -> Option<AutoTopUp> {
+    AUTO_TOP_UPS.with(|s| s.borrow().get(&miner).map(|a| a.0))
+}
+
+pub fn remove_auto_top_up(miner: Principal) {
+    AUTO_TOP_UPS.with(|s| s.borrow_mut().remove(&miner));
+}
+
+pub fn get_auto_top_ups() -> Vec<AutoTopUp> {
+    AUTO_TOP_UPS.with(|s| s.borrow().iter().map(|(_, a)| a.0).collect())
+}
+
+pub fn auto_top_up_count() -> u64 {
+    AUTO_TOP_UPS.with(|s| s.borrow().len())
+}
+
+pub fn insert_pending_withdrawal(owner: Principal, withdrawal: PendingTransfer) {
+    PENDING_WITHDRAWALS.with(|s| s.borrow_mut().insert(owner, Cbor(withdrawal)));
+}
+
+pub fn get_pending_withdrawal(owner: Principal) -> Option<PendingTransfer> {
+    PENDING_WITHDRAWALS.with(|s| s.borrow().get(&owner).map(|w| w.0))
+}
+
+pub fn remove_pending_withdrawal(owner: Principal) {
+    PENDING_WITHDRAWALS.with(|s| s.borrow_mut().remove(&owner));
+}
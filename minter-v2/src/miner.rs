@@ -1,4 +1,5 @@
-use candid::{CandidType, Principal};
+use crate::guard::GuardError;
+use candid::{CandidType, Deserialize, Principal};
 use ic_base_types::PrincipalId;
 use ic_cdk::api::call::RejectionCode;
 use ic_management_canister_types::{
@@ -22,6 +23,52 @@ pub enum Reason {
     InternalError(String),
 }
 
+/// Why the owner could not pause, resume, decommission or transfer a miner.
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub enum ManageMinerError {
+    UnknownMiner,
+    NotOwner,
+    AlreadyProcessing,
+    TooManyConcurrentRequests,
+    /// Miners cannot be transferred to the anonymous principal.
+    AnonymousNewOwner,
+    /// The call to the management canister or to the miner failed.
+    CallFailed {
+        error: String,
+    },
+}
+
+impl std::fmt::Display for ManageMinerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownMiner => write!(f, "unknown miner"),
+            Self::NotOwner => write!(f, "caller is not the owner of the miner"),
+            Self::AnonymousNewOwner => {
+                write!(f, "cannot transfer a miner to the anonymous principal")
+            }
+            Self::CallFailed { error } => write!(f, "{error}"),
+            _ => write!(f, "{self:?}"),
+        }
+    }
+}
+
+impl From<GuardError> for ManageMinerError {
+    fn from(e: GuardError) -> Self {
+        match e {
+            GuardError::AlreadyProcessing => Self::AlreadyProcessing,
+            GuardError::TooManyConcurrentRequests => Self::TooManyConcurrentRequests,
+        }
+    }
+}
+
+impl From<CallError> for ManageMinerError {
+    fn from(e: CallError) -> Self {
+        Self::CallFailed {
+            error: format!("{e:?}"),
+        }
+    }
+}
+
 impl Reason {
     fn from_reject(reject_code: RejectionCode, reject_message: String) -> Self {
         match reject_code {
@@ -109,6 +156,26 @@ pub async fn reinstall_code(
     Ok(())
 }
 
+pub async fn upgrade_code(
+    canister_id: Principal,
+    wasm_module: Vec<u8>,
+    arg: Vec<u8>,
+) -> Result<(), CallError> {
+    let install_code = InstallCodeArgs {
+        mode: CanisterInstallMode::Upgrade,
+        canister_id: PrincipalId::from(canister_id),
+        wasm_module,
+        arg,
+        compute_allocation: None,
+        memory_allocation: None,
+        sender_canister_version: None,
+    };
+
+    call("install_code", 0, &install_code).await?;
+
+    Ok(())
+}
+
 pub async fn stop_canister(canister_id: Principal) -> Result<(), CallError> {
     ic_cdk::api::management_canister::main::stop_canister(
         ic_cdk::api::management_canister::main::CanisterIdRecord { canister_id },
@@ -131,6 +198,20 @@ pub async fn start_canister(canister_id: Principal) -> Result<(), CallError> {
     })
 }
 
+pub async fn canister_status(
+    canister_id: Principal,
+) -> Result<ic_cdk::api::management_canister::main::CanisterStatusResponse, CallError> {
+    ic_cdk::api::management_canister::main::canister_status(
+        ic_cdk::api::management_canister::main::CanisterIdRecord { canister_id },
+    )
+    .await
+    .map(|(status,)| status)
+    .map_err(|(code, msg)| CallError {
+        method: "canister_status".to_string(),
+        reason: Reason::from_reject(code, msg),
+    })
+}
+
 pub async fn create_canister(cycles_for_canister_creation: u64) -> Result<Principal, CallError> {
     let create_args = CreateCanisterArgs {
         settings: Some(CanisterSettingsArgsBuilder::new().build()),
@@ -145,3 +226,65 @@ pub async fn create_canister(cycles_for_canister_creation: u64) -> Result<Princi
 
     Ok(result.get_canister_id().get().into())
 }
+
+/// Deposits `cycles` of the minter to the canister.
+pub async fn deposit_cycles(canister_id: Principal, cycles: u128) -> Result<(), CallError> {
+    ic_cdk::api::management_canister::main::deposit_cycles(
+        ic_cdk::api::management_canister::main::CanisterIdRecord { canister_id },
+        cycles,
+    )
+    .await
+    .map_err(|(code, msg)| CallError {
+        method: "deposit_cycles".to_string(),
+        reason: Reason::from_reject(code, msg),
+    })
+}
+
+pub async fn delete_canister(canister_id: Principal) -> Result<(), CallError> {
+    ic_cdk::api::management_canister::main::delete_canister(
+        ic_cdk::api::management_canister::main::CanisterIdRecord { canister_id },
+    )
+    .await
+    .map_err(|(code, msg)| CallError {
+        method: "delete_canister".to_string(),
+        reason: Reason::from_reject(code, msg),
+    })
+}
+
+/// Asks the miner to deposit its remaining cycles to the canister `to`.
+/// Returns the deposited cycles.
+pub async fn withdraw_cycles(miner: Principal, to: Principal) -> Result<u128, CallError> {
+    let result: Result<(Result<u128, String>,), _> =
+        ic_cdk::call(miner, "withdraw_cycles", (to,)).await;
+    match result {
+        Ok((Ok(cycles),)) => Ok(cycles),
+        Ok((Err(error),)) => Err(CallError {
+            method: "withdraw_cycles".to_string(),
+            reason: Reason::CanisterError(error),
+        }),
+        Err((code, msg)) => Err(CallError {
+            method: "withdraw_cycles".to_string(),
+            reason: Reason::from_reject(code, msg),
+        }),
+    }
+}
+
+#[derive(CandidType)]
+struct MinerSettings {
+    max_cycles_per_round: Option<u128>,
+    new_owner: Option<Principal>,
+}
+
+/// Sets the owner stored in the miner canister.
+pub async fn set_owner(miner: Principal, new_owner: Principal) -> Result<(), CallError> {
+    let settings = MinerSettings {
+        max_cycles_per_round: None,
+        new_owner: Some(new_owner),
+    };
+    ic_cdk::call::<_, ()>(miner, "update_miner_settings", (settings,))
+        .await
+        .map_err(|(code, msg)| CallError {
+            method: "update_miner_settings".to_string(),
+            reason: Reason::from_reject(code, msg),
+        })
+}
@@ -0,0 +1,74 @@
+use crate::memory::{get_expiration, get_miner_block_index, get_outstanding_payouts_of, get_stake};
+use crate::payouts::Payout;
+use crate::read_state;
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub struct MinerOverview {
+    pub id: Principal,
+    pub mined_blocks: u64,
+    /// Index of the ICP block that paid for the miner.
+    pub spawn_block_index: Option<u64>,
+    pub lifetime_cycles: u64,
+    pub last_submission_at: Option<u64>,
+}
+
+/// Everything an owner has at stake in the minter.
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub struct OwnerOverview {
+    pub owner: Principal,
+    pub miners: Vec<MinerOverview>,
+    /// End of the pool membership, if the owner is a member.
+    pub pool_expiration: Option<u64>,
+    /// Stake of the current pool membership.
+    pub pool_stake_e8s: u64,
+    /// ICP paid for all pool memberships of the owner.
+    pub pool_paid_e8s: u64,
+    /// BoB paid for the blocks won by the miners of the owner.
+    pub bob_earned_solo: u64,
+    /// BoB paid as share of the blocks won by the pool.
+    pub bob_earned_pool: u64,
+    /// Payouts that are still being transferred.
+    pub pending_payouts: Vec<Payout>,
+}
+
+pub fn get_owner_overview(owner: Principal, now: u64) -> OwnerOverview {
+    let (miners, pool_paid_e8s, bob_earned_solo, bob_earned_pool) = read_state(|s| {
+        let miners = s
+            .principal_to_miner
+            .get(&owner)
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|miner| MinerOverview {
+                id: miner,
+                mined_blocks: s.miner_to_mined_block.get(&miner).copied().unwrap_or(0),
+                spawn_block_index: get_miner_block_index(miner),
+                lifetime_cycles: s.miner_to_lifetime_cycles.get(&miner).copied().unwrap_or(0),
+                last_submission_at: s.miner_to_last_submission.get(&miner).copied(),
+            })
+            .collect();
+        let pool_paid_e8s = s.owner_to_pool_paid_e8s.get(&owner).copied().unwrap_or(0);
+        let bob_earned_solo = s.owner_to_bob_earned_solo.get(&owner).copied().unwrap_or(0);
+        let bob_earned_pool = s.owner_to_bob_earned_pool.get(&owner).copied().unwrap_or(0);
+        (miners, pool_paid_e8s, bob_earned_solo, bob_earned_pool)
+    });
+
+    let pool_expiration = get_expiration(owner).filter(|expiration| *expiration > now);
+    let pool_stake_e8s = match pool_expiration {
+        Some(_) => get_stake(owner).unwrap_or(0),
+        None => 0,
+    };
+
+    OwnerOverview {
+        owner,
+        miners,
+        pool_expiration,
+        pool_stake_e8s,
+        pool_paid_e8s,
+        bob_earned_solo,
+        bob_earned_pool,
+        pending_payouts: get_outstanding_payouts_of(owner),
+    }
+}
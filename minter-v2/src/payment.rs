@@ -0,0 +1,220 @@
+use crate::guard::GuardError;
+use crate::icp_blocks::{fetch_block, FetchBlockError};
+use crate::memory::{get_spawn_request, is_known_block};
+use crate::{has_payment_memo, read_state};
+use candid::{CandidType, Deserialize, Principal};
+use icp_ledger::{AccountIdentifier, Operation};
+
+/// Minimum amount of a payment, one ICP minus the transfer fee.
+pub const MIN_PAYMENT_E8S: u64 = 99_990_000;
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub enum SpawnMinerError {
+    AnonymousCaller,
+    AlreadyProcessing,
+    TooManyConcurrentRequests,
+    AlreadyConsumed,
+    IndexUnavailable {
+        error: String,
+    },
+    /// The ICP block does not exist yet, retry later.
+    NotYetIndexed {
+        block_index: u64,
+    },
+    UnknownMemo,
+    NotATransfer,
+    WrongSender,
+    WrongDestination {
+        expected: Vec<String>,
+    },
+    InsufficientAmount {
+        required: u64,
+        got: u64,
+    },
+    TransferFromFailed {
+        error: String,
+    },
+    /// The payment at `block_index` was received but the cycles minting
+    /// canister did not convert it, retry with `resume_spawn(block_index)`.
+    CyclesTopUpFailed {
+        block_index: u64,
+        error: String,
+    },
+    /// The payment at `block_index` was converted to cycles but the miner
+    /// could not be created, retry with `resume_spawn(block_index)`.
+    CanisterCreationFailed {
+        block_index: u64,
+        error: String,
+    },
+    UnknownSpawnRequest,
+    /// The cycles minting canister sent the payment back to the sender.
+    PaymentRefunded {
+        reason: String,
+    },
+    /// The spawn paid at `block_index` cannot continue on its own, see
+    /// `get_spawn_request`.
+    SpawnFailed {
+        block_index: u64,
+        error: String,
+    },
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub enum JoinPoolError {
+    AnonymousCaller,
+    AlreadyProcessing,
+    TooManyConcurrentRequests,
+    AlreadyConsumed,
+    IndexUnavailable {
+        error: String,
+    },
+    /// The ICP block does not exist yet, retry later.
+    NotYetIndexed {
+        block_index: u64,
+    },
+    UnknownMemo,
+    NotATransfer,
+    WrongSender,
+    WrongDestination {
+        expected: String,
+    },
+    InsufficientAmount {
+        required: u64,
+        got: u64,
+    },
+    InvalidDays,
+    TransferFromFailed {
+        error: String,
+    },
+    /// The payment at `block_index` was received but the cycles minting
+    /// canister did not convert it, retry with `join_pool(block_index)`.
+    CyclesTopUpFailed {
+        block_index: u64,
+        error: String,
+    },
+}
+
+/// Why an ICP block is not a valid payment to the minter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PaymentError {
+    AlreadyConsumed,
+    IndexUnavailable { error: String },
+    NotYetIndexed { block_index: u64 },
+    UnknownMemo,
+    NotATransfer,
+    WrongSender,
+    WrongDestination,
+    InsufficientAmount { required: u64, got: u64 },
+}
+
+/// Checks that the ICP block is an unused transfer of at least
+/// `min_amount_e8s` from the caller to one of the expected accounts.
+/// Returns the amount in e8s.
+pub async fn validate_payment(
+    caller: Principal,
+    block_index: u64,
+    expected_to: &[AccountIdentifier],
+    min_amount_e8s: u64,
+) -> Result<u64, PaymentError> {
+    if read_state(|s| s.miner_block_index.contains(&block_index))
+        || is_known_block(block_index)
+        || get_spawn_request(block_index).is_some()
+    {
+        return Err(PaymentError::AlreadyConsumed);
+    }
+
+    let transaction = fetch_block(block_index)
+        .await
+        .map_err(PaymentError::from)?
+        .transaction;
+
+    if !has_payment_memo(&transaction) {
+        return Err(PaymentError::UnknownMemo);
+    }
+
+    match transaction.operation {
+        Operation::Transfer {
+            from, to, amount, ..
+        } => {
+            if from != AccountIdentifier::new(ic_types::PrincipalId(caller), None) {
+                return Err(PaymentError::WrongSender);
+            }
+            if !expected_to.contains(&to) {
+                return Err(PaymentError::WrongDestination);
+            }
+            if amount.get_e8s() < min_amount_e8s {
+                return Err(PaymentError::InsufficientAmount {
+                    required: min_amount_e8s,
+                    got: amount.get_e8s(),
+                });
+            }
+            Ok(amount.get_e8s())
+        }
+        _ => Err(PaymentError::NotATransfer),
+    }
+}
+
+impl From<FetchBlockError> for PaymentError {
+    fn from(e: FetchBlockError) -> Self {
+        match e {
+            FetchBlockError::NotYetIndexed { block_index } => Self::NotYetIndexed { block_index },
+            FetchBlockError::Unavailable { error } => Self::IndexUnavailable { error },
+        }
+    }
+}
+
+impl From<GuardError> for SpawnMinerError {
+    fn from(e: GuardError) -> Self {
+        match e {
+            GuardError::AlreadyProcessing => Self::AlreadyProcessing,
+            GuardError::TooManyConcurrentRequests => Self::TooManyConcurrentRequests,
+        }
+    }
+}
+
+impl From<GuardError> for JoinPoolError {
+    fn from(e: GuardError) -> Self {
+        match e {
+            GuardError::AlreadyProcessing => Self::AlreadyProcessing,
+            GuardError::TooManyConcurrentRequests => Self::TooManyConcurrentRequests,
+        }
+    }
+}
+
+impl SpawnMinerError {
+    pub fn from_payment_error(e: PaymentError, expected: &[AccountIdentifier]) -> Self {
+        match e {
+            PaymentError::AlreadyConsumed => Self::AlreadyConsumed,
+            PaymentError::IndexUnavailable { error } => Self::IndexUnavailable { error },
+            PaymentError::NotYetIndexed { block_index } => Self::NotYetIndexed { block_index },
+            PaymentError::UnknownMemo => Self::UnknownMemo,
+            PaymentError::NotATransfer => Self::NotATransfer,
+            PaymentError::WrongSender => Self::WrongSender,
+            PaymentError::WrongDestination => Self::WrongDestination {
+                expected: expected.iter().map(|account| account.to_hex()).collect(),
+            },
+            PaymentError::InsufficientAmount { required, got } => {
+                Self::InsufficientAmount { required, got }
+            }
+        }
+    }
+}
+
+impl JoinPoolError {
+    pub fn from_payment_error(e: PaymentError, expected: AccountIdentifier) -> Self {
+        match e {
+            PaymentError::AlreadyConsumed => Self::AlreadyConsumed,
+            PaymentError::IndexUnavailable { error } => Self::IndexUnavailable { error },
+            PaymentError::NotYetIndexed { block_index } => Self::NotYetIndexed { block_index },
+            PaymentError::UnknownMemo => Self::UnknownMemo,
+            PaymentError::NotATransfer => Self::NotATransfer,
+            PaymentError::WrongSender => Self::WrongSender,
+            PaymentError::WrongDestination => Self::WrongDestination {
+                expected: expected.to_hex(),
+            },
+            PaymentError::InsufficientAmount { required, got } => {
+                Self::InsufficientAmount { required, got }
+            }
+        }
+    }
+}
@@ -0,0 +1,324 @@
+use crate::config::read_config;
+use crate::event::{process_event, EventType};
+use crate::memory::{get_outstanding_payouts, get_payout, insert_payout};
+use crate::tasks::{schedule_after, TaskType};
+use crate::{transfer, SEC_NANOS};
+use candid::{CandidType, Nat, Principal};
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferError};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::time::Duration;
+
+const MIN_RETRY_DELAY_SECS: u64 = 15;
+const MAX_RETRY_DELAY_SECS: u64 = 60 * 60;
+/// Default maximum memo length of ICRC-1 ledgers.
+const MAX_MEMO_LENGTH: usize = 32;
+
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub enum PayoutStatus {
+    Pending,
+    /// The last attempt failed, the transfer is retried at `next_attempt_at`.
+    Failed {
+        error: String,
+    },
+    Paid {
+        ledger_index: u64,
+    },
+    /// The ledger deduplication window passed after an attempt with an
+    /// unknown outcome. The payout is not retried until a controller checked
+    /// the ledger and called `reconcile_payout`.
+    NeedsReconciliation {
+        error: String,
+    },
+}
+
+/// The BoB reward owed to a single recipient of a mined block.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Payout {
+    pub block_index: u64,
+    pub recipient: Principal,
+    pub amount: u64,
+    pub status: PayoutStatus,
+    pub attempts: u32,
+    pub next_attempt_at: u64,
+    /// Sent as `created_at_time` so that the ledger deduplicates retries.
+    pub created_at_time: u64,
+    /// Whether the payout is a share of a block won by the pool.
+    #[serde(default)]
+    pub pool_share: bool,
+}
+
+impl Payout {
+    pub fn new(
+        block_index: u64,
+        recipient: Principal,
+        amount: u64,
+        created_at_time: u64,
+        now: u64,
+    ) -> Self {
+        Self {
+            block_index,
+            recipient,
+            amount,
+            status: PayoutStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            created_at_time,
+            pool_share: false,
+        }
+    }
+
+    /// The memo identifies the payout on the ledger: the ICRC-3 hash of the
+    /// block index and the recipient. Together with `created_at_time` it
+    /// makes retried transfers identical.
+    pub fn memo(&self) -> Memo {
+        let key = ICRC3Value::Array(vec![
+            ICRC3Value::Nat(Nat::from(self.block_index)),
+            ICRC3Value::Blob(ByteBuf::from(self.recipient.as_slice().to_vec())),
+        ]);
+        Memo::from(key.hash().to_vec())
+    }
+
+    pub fn is_paid(&self) -> bool {
+        matches!(self.status, PayoutStatus::Paid { .. })
+    }
+
+    /// Whether the payout is still retried by `pay_due_rewards`.
+    pub fn is_outstanding(&self) -> bool {
+        matches!(
+            self.status,
+            PayoutStatus::Pending | PayoutStatus::Failed { .. }
+        )
+    }
+
+    fn record_failure(&mut self, error: String, now: u64) {
+        self.attempts += 1;
+        self.next_attempt_at = now.saturating_add(retry_delay_secs(self.attempts) * SEC_NANOS);
+        self.status = PayoutStatus::Failed { error };
+    }
+
+    fn record_success(&mut self, ledger_index: u64) {
+        self.attempts += 1;
+        self.status = PayoutStatus::Paid { ledger_index };
+    }
+}
+
+/// Delay before the next attempt after `attempts` failed ones: doubles with
+/// every attempt, up to one hour.
+pub fn retry_delay_secs(attempts: u32) -> u64 {
+    MIN_RETRY_DELAY_SECS
+        .saturating_mul(1 << attempts.min(16))
+        .min(MAX_RETRY_DELAY_SECS)
+}
+
+/// Splits `amount` among the stakeholders in proportion to their stake.
+/// Shares are rounded down, the undistributed remainder is returned so that
+/// it can be carried forward. Without any stake, everything is carried.
+pub fn split_pro_rata(amount: u64, stakes: &[(Principal, u64)]) -> (Vec<(Principal, u64)>, u64) {
+    let total_stake: u128 = stakes.iter().map(|(_, stake)| *stake as u128).sum();
+    if total_stake == 0 {
+        return (vec![], amount);
+    }
+
+    let shares: Vec<(Principal, u64)> = stakes
+        .iter()
+        .map(|(owner, stake)| {
+            let share = amount as u128 * *stake as u128 / total_stake;
+            (*owner, share as u64)
+        })
+        .collect();
+    let distributed: u64 = shares.iter().map(|(_, share)| share).sum();
+    (shares, amount - distributed)
+}
+
+/// Transfers all outstanding payouts that are due and schedules the next
+/// attempt for the ones that failed.
+pub async fn pay_due_rewards() {
+    let ledger_canister_id = read_config(|c| c.bob_ledger_id);
+
+    for (block_index, recipient) in get_outstanding_payouts() {
+        let mut payout = match get_payout(recipient, block_index) {
+            Some(payout) => payout,
+            None => continue,
+        };
+        if payout.next_attempt_at > ic_cdk::api::time() {
+            continue;
+        }
+
+        match transfer(
+            recipient,
+            payout.amount.into(),
+            Some(Nat::from(0_u8)),
+            Some(payout.memo()),
+            Some(payout.created_at_time),
+            ledger_canister_id,
+        )
+        .await
+        {
+            Ok(ledger_index) => payout.record_success(ledger_index),
+            Err(TransferError::Duplicate { duplicate_of }) => {
+                // An earlier attempt went through but its response was lost.
+                payout.record_success(duplicate_of.0.try_into().unwrap())
+            }
+            Err(TransferError::TooOld) if payout.attempts == 0 => {
+                // Nothing was sent before, so a fresh timestamp cannot
+                // duplicate an earlier transfer.
+                let now = ic_cdk::api::time();
+                payout.created_at_time = now;
+                payout.record_failure(format!("{:?}", TransferError::TooOld), now);
+            }
+            Err(TransferError::TooOld) => {
+                // An earlier attempt may have gone through, but the ledger
+                // can no longer tell: resending could pay twice.
+                payout.attempts += 1;
+                payout.status = PayoutStatus::NeedsReconciliation {
+                    error: format!("{:?}", TransferError::TooOld),
+                };
+            }
+            Err(e) => payout.record_failure(format!("{e:?}"), ic_cdk::api::time()),
+        }
+        process_event(match &payout.status {
+            PayoutStatus::Paid { ledger_index } => EventType::RewardPaid {
+                block_index,
+                recipient,
+                amount: payout.amount,
+                ledger_index: *ledger_index,
+                pool_share: payout.pool_share,
+            },
+            PayoutStatus::Failed { error } | PayoutStatus::NeedsReconciliation { error } => {
+                EventType::RewardFailed {
+                    block_index,
+                    recipient,
+                    amount: payout.amount,
+                    error: error.clone(),
+                }
+            }
+            PayoutStatus::Pending => unreachable!("bug: payout attempt without outcome"),
+        });
+        insert_payout(payout);
+    }
+
+    schedule_next_attempt();
+}
+
+/// Resolves a payout that needs reconciliation. With the `ledger_index` of
+/// the transfer found on the ledger, the payout is marked as paid. Without it,
+/// the payout is sent again with a fresh timestamp.
+pub fn reconcile_payout(
+    recipient: Principal,
+    block_index: u64,
+    ledger_index: Option<u64>,
+) -> Result<Payout, String> {
+    let mut payout = get_payout(recipient, block_index)
+        .ok_or_else(|| format!("no payout of block {block_index} to {recipient}"))?;
+    if !matches!(payout.status, PayoutStatus::NeedsReconciliation { .. }) {
+        return Err(format!(
+            "the payout does not need reconciliation: {:?}",
+            payout.status
+        ));
+    }
+
+    match ledger_index {
+        Some(ledger_index) => {
+            payout.status = PayoutStatus::Paid { ledger_index };
+            process_event(EventType::RewardPaid {
+                block_index,
+                recipient,
+                amount: payout.amount,
+                ledger_index,
+                pool_share: payout.pool_share,
+            });
+        }
+        None => {
+            let now = ic_cdk::api::time();
+            payout.status = PayoutStatus::Pending;
+            payout.created_at_time = now;
+            payout.next_attempt_at = now;
+            schedule_after(Duration::from_secs(0), TaskType::MineBob);
+        }
+    }
+    insert_payout(payout.clone());
+    Ok(payout)
+}
+
+fn schedule_next_attempt() {
+    let next_attempt_at = get_outstanding_payouts()
+        .into_iter()
+        .filter_map(|(block_index, recipient)| get_payout(recipient, block_index))
+        .map(|payout| payout.next_attempt_at)
+        .min();
+
+    if let Some(next_attempt_at) = next_attempt_at {
+        let delay_secs = next_attempt_at.saturating_sub(ic_cdk::api::time()) / SEC_NANOS;
+        schedule_after(Duration::from_secs(delay_secs + 1), TaskType::MineBob);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memo_is_deterministic_and_distinct() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let payout = |block_index, recipient| Payout::new(block_index, recipient, 1, 0, 0);
+
+        assert_eq!(payout(7, alice).memo(), payout(7, alice).memo());
+        assert_ne!(payout(7, alice).memo(), payout(8, alice).memo());
+        assert_ne!(payout(7, alice).memo(), payout(7, bob).memo());
+        let mut carol = [1; 29];
+        carol[28] = 3;
+        assert_ne!(
+            payout(7, alice).memo(),
+            payout(7, Principal::from_slice(&carol)).memo()
+        );
+        assert!(payout(u64::MAX, alice).memo().0.len() <= MAX_MEMO_LENGTH);
+    }
+
+    #[test]
+    fn test_split_pro_rata() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+
+        assert_eq!(
+            split_pro_rata(1_000, &[(alice, 100), (bob, 300)]),
+            (vec![(alice, 250), (bob, 750)], 0)
+        );
+        assert_eq!(
+            split_pro_rata(1_000, &[(alice, 1), (bob, 2)]),
+            (vec![(alice, 333), (bob, 666)], 1)
+        );
+        assert_eq!(split_pro_rata(1_000, &[]), (vec![], 1_000));
+        assert_eq!(split_pro_rata(1_000, &[(alice, 0)]), (vec![], 1_000));
+    }
+
+    #[test]
+    fn test_split_pro_rata_never_exceeds_amount() {
+        let stakes: Vec<_> = (1..=7_u8)
+            .map(|i| (Principal::from_slice(&[i; 29]), u64::MAX / i as u64))
+            .collect();
+        for amount in [0, 1, 7, 60_000_000_000, u64::MAX] {
+            let (shares, remainder) = split_pro_rata(amount, &stakes);
+            let distributed: u128 = shares.iter().map(|(_, share)| *share as u128).sum();
+            assert_eq!(distributed + remainder as u128, amount as u128);
+            assert!(remainder < stakes.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_retry_delay_is_bounded() {
+        let mut payout = Payout::new(0, Principal::anonymous(), 1, 0, 0);
+        payout.record_failure("error".to_string(), 0);
+        assert_eq!(payout.next_attempt_at, 2 * MIN_RETRY_DELAY_SECS * SEC_NANOS);
+        for _ in 0..100 {
+            payout.record_failure("error".to_string(), 0);
+        }
+        assert_eq!(payout.next_attempt_at, MAX_RETRY_DELAY_SECS * SEC_NANOS);
+        assert!(!payout.is_paid());
+
+        payout.record_success(42);
+        assert!(payout.is_paid());
+    }
+}
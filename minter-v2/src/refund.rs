@@ -0,0 +1,242 @@
+use crate::auto_top_up::deposit_subaccount;
+use crate::config::read_config;
+use crate::event::{process_event, EventType};
+use crate::guard::GuardError;
+use crate::icp_blocks::{fetch_block, FetchBlockError};
+use crate::memory::{
+    get_refund, get_spawn_request, insert_block_index, insert_refund, is_known_block,
+};
+use crate::spawn::SpawnStep;
+use crate::{
+    read_state, transfer_from_subaccount, SubaccountTransferError, MAINNET_LEDGER_CANISTER_ID,
+};
+use candid::{CandidType, Nat, Principal};
+use ic_types::PrincipalId;
+use icp_ledger::{AccountIdentifier, Operation};
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferError};
+use serde::{Deserialize, Serialize};
+
+/// Fee of ICP ledger transfers, deducted from the refunded amount.
+pub const ICP_TRANSFER_FEE_E8S: u64 = 10_000;
+
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub enum RefundStatus {
+    Pending,
+    /// The transfer failed, calling `request_refund` again retries it.
+    Failed {
+        error: String,
+    },
+    Refunded {
+        ledger_index: u64,
+    },
+    /// The ledger no longer deduplicates a retry and an earlier attempt may
+    /// have gone through, so the refund is not sent again.
+    Expired,
+}
+
+/// ICP sent back to the sender of a payment that the minter did not use,
+/// from the deposit account of the sender.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Refund {
+    /// Index of the ICP block of the refunded payment.
+    pub block_index: u64,
+    pub recipient: Principal,
+    /// Refunded amount, the payment minus the transfer fee.
+    pub amount_e8s: u64,
+    /// Sent as `created_at_time` so that the ledger deduplicates retries.
+    pub created_at_time: u64,
+    pub status: RefundStatus,
+}
+
+impl Refund {
+    pub fn is_refunded(&self) -> bool {
+        matches!(self.status, RefundStatus::Refunded { .. })
+    }
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub enum RefundError {
+    AnonymousCaller,
+    AlreadyProcessing,
+    TooManyConcurrentRequests,
+    /// The payment paid for a miner or a pool membership, or was refunded.
+    AlreadyConsumed,
+    IndexUnavailable {
+        error: String,
+    },
+    /// The ICP block does not exist yet, retry later.
+    NotYetIndexed {
+        block_index: u64,
+    },
+    NotATransfer,
+    WrongSender,
+    /// The ICP was not sent to the deposit account of the caller.
+    NotAPayment,
+    /// The payment was sent to the cycles minting canister, which only
+    /// converts it to cycles. Use it with `spawn_miner` or `join_pool`, or
+    /// credit its cycles to a miner of the caller with `top_up_miner`.
+    HeldByCyclesMinter,
+    /// The payment already paid for the creation of a miner, resume the
+    /// spawn instead.
+    SpawnInProgress,
+    /// The cycles minting canister already sent the payment back.
+    RefundedByCyclesMinter {
+        reason: String,
+    },
+    AmountTooLow {
+        amount_e8s: u64,
+    },
+    /// The refund is recorded but the transfer failed, call
+    /// `request_refund(block_index)` again to retry.
+    TransferFailed {
+        error: String,
+    },
+    /// Whether the refund was sent can no longer be told, see
+    /// `RefundStatus::Expired`.
+    Expired,
+}
+
+impl From<GuardError> for RefundError {
+    fn from(e: GuardError) -> Self {
+        match e {
+            GuardError::AlreadyProcessing => Self::AlreadyProcessing,
+            GuardError::TooManyConcurrentRequests => Self::TooManyConcurrentRequests,
+        }
+    }
+}
+
+/// Refunds the ICP at `block_index` to its sender `caller`.
+///
+/// Only ICP the caller sent to its deposit account at the minter without
+/// `deposit_icp` is refunded, the amount minus the fee goes back from that
+/// same account. Payments to the cycles minting canister stay there, the
+/// minter cannot move them: `top_up_miner` converts them to cycles of a
+/// miner instead. The block index is consumed before the transfer, so the
+/// ICP can no longer be refunded twice.
+pub async fn request_refund(caller: Principal, block_index: u64) -> Result<Refund, RefundError> {
+    let refund = match get_refund(caller, block_index) {
+        Some(refund) if refund.is_refunded() => return Ok(refund),
+        Some(Refund {
+            status: RefundStatus::Expired,
+            ..
+        }) => return Err(RefundError::Expired),
+        Some(refund) => refund,
+        None => start_refund(caller, block_index).await?,
+    };
+    send_refund(refund).await
+}
+
+async fn start_refund(caller: Principal, block_index: u64) -> Result<Refund, RefundError> {
+    check_unused(block_index)?;
+
+    let transaction = fetch_block(block_index)
+        .await
+        .map_err(|e| match e {
+            FetchBlockError::NotYetIndexed { block_index } => {
+                RefundError::NotYetIndexed { block_index }
+            }
+            FetchBlockError::Unavailable { error } => RefundError::IndexUnavailable { error },
+        })?
+        .transaction;
+    let amount_e8s = match transaction.operation {
+        Operation::Transfer {
+            from, to, amount, ..
+        } => {
+            if from != AccountIdentifier::new(PrincipalId(caller), None) {
+                return Err(RefundError::WrongSender);
+            }
+            let config = read_config(|c| c.clone());
+            if config.spawn_payment_accounts().contains(&to) || config.pool_payment_account() == to
+            {
+                return Err(RefundError::HeldByCyclesMinter);
+            }
+            let deposit_account =
+                AccountIdentifier::new(PrincipalId(ic_cdk::id()), Some(deposit_subaccount(caller)));
+            if to != deposit_account {
+                return Err(RefundError::NotAPayment);
+            }
+            amount.get_e8s()
+        }
+        _ => return Err(RefundError::NotATransfer),
+    };
+    if amount_e8s <= ICP_TRANSFER_FEE_E8S {
+        return Err(RefundError::AmountTooLow { amount_e8s });
+    }
+    check_unused(block_index)?;
+
+    let now = ic_cdk::api::time();
+    let refund = Refund {
+        block_index,
+        recipient: caller,
+        amount_e8s: amount_e8s - ICP_TRANSFER_FEE_E8S,
+        created_at_time: now,
+        status: RefundStatus::Pending,
+    };
+    insert_block_index(block_index);
+    insert_refund(refund.clone());
+    Ok(refund)
+}
+
+/// Fails if the payment paid for something or is being used by a spawn.
+fn check_unused(block_index: u64) -> Result<(), RefundError> {
+    if read_state(|s| s.miner_block_index.contains(&block_index)) || is_known_block(block_index) {
+        return Err(RefundError::AlreadyConsumed);
+    }
+    match get_spawn_request(block_index).map(|r| r.step) {
+        None | Some(SpawnStep::Paid) => Ok(()),
+        Some(SpawnStep::Refunded { reason }) => Err(RefundError::RefundedByCyclesMinter { reason }),
+        Some(SpawnStep::ToppedUp)
+        | Some(SpawnStep::CreatingCanister)
+        | Some(SpawnStep::CanisterCreated { .. })
+        | Some(SpawnStep::CreationUnknown)
+        | Some(SpawnStep::Installed { .. }) => Err(RefundError::SpawnInProgress),
+        Some(SpawnStep::Failed { .. }) => Err(RefundError::AlreadyConsumed),
+    }
+}
+
+async fn send_refund(mut refund: Refund) -> Result<Refund, RefundError> {
+    let result = transfer_from_subaccount(
+        Some(deposit_subaccount(refund.recipient).0),
+        refund.recipient,
+        Nat::from(refund.amount_e8s),
+        Some(Nat::from(ICP_TRANSFER_FEE_E8S)),
+        Some(Memo::from(refund.block_index)),
+        Some(refund.created_at_time),
+        MAINNET_LEDGER_CANISTER_ID,
+    )
+    .await;
+    match result {
+        Ok(ledger_index) => refund.status = RefundStatus::Refunded { ledger_index },
+        Err(SubaccountTransferError::Ledger(TransferError::Duplicate { duplicate_of })) => {
+            refund.status = RefundStatus::Refunded {
+                ledger_index: duplicate_of.0.try_into().unwrap(),
+            }
+        }
+        Err(SubaccountTransferError::Ledger(TransferError::TooOld)) => {
+            refund.status = RefundStatus::Expired
+        }
+        Err(e) => {
+            refund.status = RefundStatus::Failed {
+                error: format!("{e:?}"),
+            }
+        }
+    }
+    insert_refund(refund.clone());
+
+    match &refund.status {
+        RefundStatus::Refunded { ledger_index } => {
+            process_event(EventType::PaymentRefunded {
+                block_index: refund.block_index,
+                recipient: refund.recipient,
+                amount_e8s: refund.amount_e8s,
+                ledger_index: *ledger_index,
+            });
+            Ok(refund)
+        }
+        RefundStatus::Failed { error } => Err(RefundError::TransferFailed {
+            error: error.clone(),
+        }),
+        RefundStatus::Expired => Err(RefundError::Expired),
+        RefundStatus::Pending => unreachable!("bug: refund attempt without outcome"),
+    }
+}
@@ -0,0 +1,156 @@
+use candid::Principal;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha20Rng;
+
+/// Sum of the burned cycles, saturating at `u64::MAX`.
+pub fn total_cycles(participants: &[(Principal, u64)]) -> u64 {
+    participants
+        .iter()
+        .fold(0_u64, |total, (_, cycles)| total.saturating_add(*cycles))
+}
+
+/// Picks a miner with a probability proportional to the cycles it burned
+/// in the round. The participants are shuffled with ChaCha20 seeded by the
+/// randomness before walking the cumulative sum of burned cycles.
+///
+/// The random point is taken from the first 8 bytes of the randomness, or
+/// the first 16 bytes if the burned cycles do not fit into a `u64`.
+pub fn select_winner(participants: &[(Principal, u64)], randomness: [u8; 32]) -> Option<Principal> {
+    let total_cycles: u128 = participants.iter().map(|(_, cycles)| *cycles as u128).sum();
+    if total_cycles == 0 {
+        return None;
+    }
+
+    let random_value = if total_cycles <= u64::MAX as u128 {
+        (u64::from_le_bytes(randomness[..8].try_into().unwrap()) as u128) % total_cycles
+    } else {
+        u128::from_le_bytes(randomness[..16].try_into().unwrap()) % total_cycles
+    };
+
+    let mut entries: Vec<_> = participants.iter().collect();
+    let mut rng = ChaCha20Rng::from_seed(randomness);
+
+    entries.shuffle(&mut rng);
+
+    let mut cumulative_sum: u128 = 0;
+    entries
+        .into_iter()
+        .find(|(_, value)| {
+            cumulative_sum += *value as u128;
+            cumulative_sum > random_value
+        })
+        .map(|(key, _)| *key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    fn principal(i: u8) -> Principal {
+        Principal::from_slice(&[i; 29])
+    }
+
+    fn randomness(rng: &mut StdRng) -> [u8; 32] {
+        let mut randomness = [0; 32];
+        rng.fill(&mut randomness);
+        randomness
+    }
+
+    #[test]
+    fn test_no_participants_or_no_cycles() {
+        assert_eq!(select_winner(&[], [0; 32]), None);
+        assert_eq!(
+            select_winner(&[(principal(1), 0), (principal(2), 0)], [0; 32]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_single_participant_always_wins() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for cycles in [1, 10_000_000_001, u64::MAX] {
+            for _ in 0..100 {
+                assert_eq!(
+                    select_winner(&[(principal(1), cycles)], randomness(&mut rng)),
+                    Some(principal(1))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_selection_is_deterministic() {
+        let participants: Vec<_> = (1..=10).map(|i| (principal(i), i as u64)).collect();
+        let mut rng = StdRng::seed_from_u64(2);
+        for _ in 0..100 {
+            let randomness = randomness(&mut rng);
+            assert_eq!(
+                select_winner(&participants, randomness),
+                select_winner(&participants, randomness)
+            );
+        }
+    }
+
+    #[test]
+    fn test_winner_is_a_participant_with_burned_cycles() {
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..1_000 {
+            let count = rng.gen_range(1..20);
+            let participants: Vec<_> = (0..count)
+                .map(|i| (principal(i), rng.gen_range(0..3) * rng.gen::<u32>() as u64))
+                .collect();
+            let winner = select_winner(&participants, randomness(&mut rng));
+            if total_cycles(&participants) == 0 {
+                assert_eq!(winner, None);
+            } else {
+                let winner = winner.expect("a winner must be selected");
+                assert!(participants
+                    .iter()
+                    .any(|(miner, cycles)| *miner == winner && *cycles > 0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_selection_probability_is_proportional_to_burned_cycles() {
+        const ROUNDS: u64 = 20_000;
+        let participants: Vec<_> = (1..=4)
+            .map(|i| (principal(i), i as u64 * 10_000_000_000))
+            .collect();
+        let total = total_cycles(&participants);
+
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut wins: BTreeMap<Principal, u64> = BTreeMap::new();
+        for _ in 0..ROUNDS {
+            let winner = select_winner(&participants, randomness(&mut rng)).unwrap();
+            *wins.entry(winner).or_default() += 1;
+        }
+
+        for (miner, cycles) in participants {
+            let expected = cycles as f64 / total as f64;
+            let observed = *wins.get(&miner).unwrap_or(&0) as f64 / ROUNDS as f64;
+            assert!(
+                (expected - observed).abs() < 0.02,
+                "{miner}: expected {expected}, observed {observed}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_overflowing_sum_of_cycles() {
+        let participants = [(principal(1), u64::MAX), (principal(2), u64::MAX)];
+        assert_eq!(total_cycles(&participants), u64::MAX);
+
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut wins: BTreeMap<Principal, u64> = BTreeMap::new();
+        for _ in 0..1_000 {
+            let winner = select_winner(&participants, randomness(&mut rng)).unwrap();
+            *wins.entry(winner).or_default() += 1;
+        }
+        assert_eq!(wins.len(), 2);
+    }
+}
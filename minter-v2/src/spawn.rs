@@ -0,0 +1,330 @@
+use crate::event::{process_event, EventType};
+use crate::guard::GuardPrincipal;
+use crate::memory::{
+    get_pending_spawns, get_spawn_request, insert_new_miner, insert_spawn_request,
+};
+use crate::miner::{create_canister, install_code, reinstall_code};
+use crate::payment::SpawnMinerError;
+use crate::payouts::retry_delay_secs;
+use crate::tasks::{schedule_after, TaskType};
+use crate::{miner_wasm, notify_top_up, SEC_NANOS};
+use candid::{CandidType, Encode, Principal};
+use cycles_minting_canister::NotifyError;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Cycles the minter attaches to the creation of a miner.
+pub const CYCLES_FOR_CREATION: u64 = 2_500_000_000_000;
+
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub enum SpawnStep {
+    /// The ICP payment is verified but not converted to cycles yet.
+    Paid,
+    /// The cycles minting canister converted the payment to cycles of the minter.
+    ToppedUp,
+    /// The canister of the miner is being created. Persisted before the
+    /// call, so that a creation whose outcome was lost is not repeated.
+    CreatingCanister,
+    CanisterCreated {
+        miner: Principal,
+    },
+    /// The miner code is installed and the miner is registered.
+    Installed {
+        miner: Principal,
+    },
+    /// The cycles minting canister refused the payment and sent it back.
+    Refunded {
+        reason: String,
+    },
+    /// The outcome of the canister creation was lost. The spawn waits for a
+    /// controller to look for the canister and call `recover_spawn`.
+    CreationUnknown,
+    /// The cycles minting canister no longer accepts the payment.
+    Failed {
+        error: String,
+    },
+}
+
+/// A miner spawn, keyed by the index of the ICP block that paid for it.
+/// Each completed step is persisted before the next one starts, so an
+/// interrupted spawn resumes where it stopped.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct SpawnRequest {
+    pub block_index: u64,
+    pub owner: Principal,
+    pub step: SpawnStep,
+    /// Failed attempts at the current step.
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: u64,
+}
+
+impl SpawnRequest {
+    pub fn new(block_index: u64, owner: Principal, now: u64) -> Self {
+        Self {
+            block_index,
+            owner,
+            step: SpawnStep::Paid,
+            attempts: 0,
+            last_error: None,
+            // Leave the first attempt to the caller, the background task only
+            // picks the request up if that attempt never finishes.
+            next_attempt_at: now.saturating_add(retry_delay_secs(0) * SEC_NANOS),
+        }
+    }
+
+    pub fn is_final(&self) -> bool {
+        matches!(
+            self.step,
+            SpawnStep::Installed { .. }
+                | SpawnStep::Refunded { .. }
+                | SpawnStep::CreationUnknown
+                | SpawnStep::Failed { .. }
+        )
+    }
+
+    fn advance(&mut self, step: SpawnStep) {
+        self.step = step;
+        self.attempts = 0;
+        self.last_error = None;
+    }
+
+    /// Schedules another attempt at the current step. The spawn never gives
+    /// up on its own: that would strand the payment or the converted cycles,
+    /// or leak the created canister.
+    fn record_failure(&mut self, error: String, now: u64) {
+        self.attempts += 1;
+        self.next_attempt_at = now.saturating_add(retry_delay_secs(self.attempts) * SEC_NANOS);
+        self.last_error = Some(error);
+    }
+
+    /// Stops the spawn at `step` until a controller recovers it.
+    fn halt(&mut self, step: SpawnStep, error: String) -> SpawnMinerError {
+        self.step = step;
+        self.last_error = Some(error.clone());
+        SpawnMinerError::SpawnFailed {
+            block_index: self.block_index,
+            error,
+        }
+    }
+}
+
+/// Records a verified payment and runs the spawn.
+pub async fn start_spawn(owner: Principal, block_index: u64) -> Result<Principal, SpawnMinerError> {
+    insert_spawn_request(SpawnRequest::new(block_index, owner, ic_cdk::api::time()));
+    schedule_next_attempt();
+    advance_spawn(block_index).await
+}
+
+/// Runs the remaining steps of the spawn request until the miner is
+/// installed or a step fails. The caller must hold the guard of the owner.
+pub async fn advance_spawn(block_index: u64) -> Result<Principal, SpawnMinerError> {
+    loop {
+        let mut request =
+            get_spawn_request(block_index).ok_or(SpawnMinerError::UnknownSpawnRequest)?;
+
+        let outcome = match request.step.clone() {
+            SpawnStep::Paid => match notify_top_up(block_index, ic_cdk::id()).await {
+                Ok(_) => Ok(SpawnStep::ToppedUp),
+                Err(NotifyError::Refunded { reason, .. }) => Ok(SpawnStep::Refunded { reason }),
+                Err(e @ NotifyError::TransactionTooOld(_)) => {
+                    let error = e.to_string();
+                    let err = request.halt(
+                        SpawnStep::Failed {
+                            error: error.clone(),
+                        },
+                        error,
+                    );
+                    insert_spawn_request(request);
+                    return Err(err);
+                }
+                Err(e) => Err(e.to_string()),
+            },
+            SpawnStep::ToppedUp => {
+                request.step = SpawnStep::CreatingCanister;
+                insert_spawn_request(request.clone());
+                match create_canister(CYCLES_FOR_CREATION).await {
+                    Ok(miner) => Ok(SpawnStep::CanisterCreated { miner }),
+                    Err(e) => {
+                        // The call was rejected, no canister was created.
+                        request.step = SpawnStep::ToppedUp;
+                        Err(format!("{} - {:?}", e.method, e.reason))
+                    }
+                }
+            }
+            SpawnStep::CreatingCanister => {
+                // A canister may exist that the minter cannot find, creating
+                // another one would leak it.
+                let error = "the outcome of the canister creation is unknown".to_string();
+                let err = request.halt(SpawnStep::CreationUnknown, error);
+                insert_spawn_request(request);
+                return Err(err);
+            }
+            SpawnStep::CanisterCreated { miner } => {
+                let arg = Encode!(&request.owner).unwrap();
+                // The response of a previous install may have been lost,
+                // reinstalling the fresh miner is harmless.
+                let result = if request.attempts == 0 {
+                    install_code(miner, miner_wasm().to_vec(), arg).await
+                } else {
+                    reinstall_code(miner, miner_wasm().to_vec(), arg).await
+                };
+                result
+                    .map(|()| SpawnStep::Installed { miner })
+                    .map_err(|e| format!("{} - {:?}", e.method, e.reason))
+            }
+            SpawnStep::Installed { miner } => return Ok(miner),
+            SpawnStep::Refunded { reason } => {
+                return Err(SpawnMinerError::PaymentRefunded { reason })
+            }
+            SpawnStep::CreationUnknown => {
+                return Err(SpawnMinerError::SpawnFailed {
+                    block_index,
+                    error: request.last_error.unwrap_or_default(),
+                })
+            }
+            SpawnStep::Failed { error } => {
+                return Err(SpawnMinerError::SpawnFailed { block_index, error })
+            }
+        };
+
+        match outcome {
+            Ok(step) => {
+                if let SpawnStep::Installed { miner } = step {
+                    process_event(EventType::MinerSpawned {
+                        miner,
+                        owner: request.owner,
+                        block_index,
+                    });
+                    insert_new_miner(miner, request.owner, block_index);
+                }
+                request.advance(step);
+                insert_spawn_request(request);
+            }
+            Err(error) => {
+                let err = match request.step {
+                    SpawnStep::Paid => SpawnMinerError::CyclesTopUpFailed {
+                        block_index,
+                        error: error.clone(),
+                    },
+                    _ => SpawnMinerError::CanisterCreationFailed {
+                        block_index,
+                        error: error.clone(),
+                    },
+                };
+                request.record_failure(error, ic_cdk::api::time());
+                insert_spawn_request(request);
+                schedule_next_attempt();
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Finishes the spawns that are due for another attempt. Spawns of owners
+/// with a call in progress are left to that call.
+pub async fn resume_pending_spawns() {
+    for block_index in get_pending_spawns() {
+        let request = match get_spawn_request(block_index) {
+            Some(request) => request,
+            None => continue,
+        };
+        if request.next_attempt_at > ic_cdk::api::time() {
+            continue;
+        }
+        let _guard = match GuardPrincipal::new(request.owner) {
+            Ok(guard) => guard,
+            Err(_) => continue,
+        };
+        let _ = advance_spawn(block_index).await;
+    }
+
+    schedule_next_attempt();
+}
+
+/// Resumes a spawn whose canister creation has an unknown outcome. `miner`
+/// is the canister created for it, if a controller found one among the
+/// canisters of the minter. Without it, the spawn creates a new canister.
+pub fn recover_spawn(block_index: u64, miner: Option<Principal>) -> Result<SpawnRequest, String> {
+    let mut request = get_spawn_request(block_index)
+        .ok_or_else(|| format!("no spawn request for block {block_index}"))?;
+    if request.step != SpawnStep::CreationUnknown {
+        return Err(format!("the spawn cannot be recovered: {:?}", request.step));
+    }
+
+    request.advance(match miner {
+        Some(miner) => SpawnStep::CanisterCreated { miner },
+        None => SpawnStep::ToppedUp,
+    });
+    request.next_attempt_at = ic_cdk::api::time();
+    insert_spawn_request(request.clone());
+    schedule_next_attempt();
+    Ok(request)
+}
+
+fn schedule_next_attempt() {
+    let next_attempt_at = get_pending_spawns()
+        .into_iter()
+        .filter_map(get_spawn_request)
+        .map(|request| request.next_attempt_at)
+        .min();
+
+    if let Some(next_attempt_at) = next_attempt_at {
+        let delay_secs = next_attempt_at.saturating_sub(ic_cdk::api::time()) / SEC_NANOS;
+        schedule_after(Duration::from_secs(delay_secs + 1), TaskType::ResumeSpawns);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn_request_steps() {
+        let miner = Principal::from_slice(&[1; 29]);
+        let mut request = SpawnRequest::new(7, Principal::anonymous(), 0);
+        assert!(!request.is_final());
+
+        request.record_failure("error".to_string(), 0);
+        assert_eq!(request.attempts, 1);
+        assert_eq!(request.last_error, Some("error".to_string()));
+
+        request.advance(SpawnStep::CanisterCreated { miner });
+        assert_eq!(request.attempts, 0);
+        assert_eq!(request.last_error, None);
+        assert!(!request.is_final());
+
+        request.advance(SpawnStep::Installed { miner });
+        assert!(request.is_final());
+
+        assert!(SpawnRequest {
+            step: SpawnStep::Refunded {
+                reason: "reason".to_string()
+            },
+            ..request
+        }
+        .is_final());
+
+        // a created canister is never abandoned
+        let mut request = SpawnRequest {
+            step: SpawnStep::CanisterCreated { miner },
+            ..SpawnRequest::new(8, Principal::anonymous(), 0)
+        };
+        for _ in 0..100 {
+            request.record_failure("error".to_string(), 0);
+        }
+        assert_eq!(request.step, SpawnStep::CanisterCreated { miner });
+        assert!(!request.is_final());
+        assert_eq!(request.next_attempt_at, retry_delay_secs(100) * SEC_NANOS);
+
+        let err = request.halt(SpawnStep::CreationUnknown, "error".to_string());
+        assert_eq!(
+            err,
+            SpawnMinerError::SpawnFailed {
+                block_index: 8,
+                error: "error".to_string()
+            }
+        );
+        assert!(request.is_final());
+    }
+}
@@ -15,6 +15,11 @@ thread_local! {
 pub enum TaskType {
     ProcessLogic,
     MineBob,
+    ResumeSpawns,
+    MonitorMiners,
+    AutoTopUps,
+    IndexLeaderboard,
+    BackfillStats,
 }
 
 #[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq, CandidType)]
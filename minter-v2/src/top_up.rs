@@ -0,0 +1,243 @@
+use crate::config::read_config;
+use crate::event::{process_event, EventType};
+use crate::guard::GuardError;
+use crate::memory::{get_miner_owner, get_top_up, insert_block_index, insert_top_up};
+use crate::miner::deposit_cycles;
+use crate::payment::{validate_payment, PaymentError};
+use crate::refund::ICP_TRANSFER_FEE_E8S;
+use crate::{notify_top_up, MAINNET_CYCLE_MINTER_CANISTER_ID};
+use candid::{CandidType, Principal};
+use cycles_minting_canister::NotifyError;
+use ic_types::PrincipalId;
+use icp_ledger::{AccountIdentifier, Subaccount};
+use serde::{Deserialize, Serialize};
+
+/// Minimum amount of a top-up payment, anything above the transfer fee
+/// the cycles minting canister pays to move it.
+pub const MIN_TOP_UP_E8S: u64 = ICP_TRANSFER_FEE_E8S + 1;
+
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub enum TopUpStatus {
+    Pending,
+    /// The notification failed, calling `top_up_miner` again retries it.
+    Failed {
+        error: String,
+    },
+    /// The payment held for the minter is converted to cycles of the
+    /// minter, which did not deposit them to the miner yet.
+    Converted {
+        cycles: u128,
+    },
+    ToppedUp {
+        cycles: u128,
+    },
+    /// The cycles minting canister refused the payment and sent it back.
+    Refunded {
+        reason: String,
+    },
+}
+
+/// An ICP payment converted to cycles of a miner.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct MinerTopUp {
+    pub miner: Principal,
+    /// Index of the ICP block of the payment.
+    pub block_index: u64,
+    /// The minter for top-ups paid from the deposit of the owner.
+    pub payer: Principal,
+    pub amount_e8s: u64,
+    pub created_at: u64,
+    pub status: TopUpStatus,
+    /// The payment was sent to a spawn or pool payment account instead of
+    /// the top-up account: its cycles go to the minter, which deposits them
+    /// to the miner.
+    #[serde(default)]
+    pub held_by_minter: bool,
+}
+
+#[derive(Clone, CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub enum TopUpMinerError {
+    AnonymousCaller,
+    AlreadyProcessing,
+    TooManyConcurrentRequests,
+    UnknownMiner,
+    AlreadyConsumed,
+    IndexUnavailable {
+        error: String,
+    },
+    /// The ICP block does not exist yet, retry later.
+    NotYetIndexed {
+        block_index: u64,
+    },
+    UnknownMemo,
+    NotATransfer,
+    WrongSender,
+    WrongDestination {
+        expected: String,
+    },
+    InsufficientAmount {
+        required: u64,
+        got: u64,
+    },
+    /// The payment is recorded but the cycles minting canister did not
+    /// convert it, retry with `top_up_miner(miner, block_index)`.
+    CyclesTopUpFailed {
+        block_index: u64,
+        error: String,
+    },
+    /// The cycles minting canister sent the payment back to the sender.
+    PaymentRefunded {
+        reason: String,
+    },
+}
+
+impl From<GuardError> for TopUpMinerError {
+    fn from(e: GuardError) -> Self {
+        match e {
+            GuardError::AlreadyProcessing => Self::AlreadyProcessing,
+            GuardError::TooManyConcurrentRequests => Self::TooManyConcurrentRequests,
+        }
+    }
+}
+
+impl TopUpMinerError {
+    pub fn from_payment_error(e: PaymentError, expected: AccountIdentifier) -> Self {
+        match e {
+            PaymentError::AlreadyConsumed => Self::AlreadyConsumed,
+            PaymentError::IndexUnavailable { error } => Self::IndexUnavailable { error },
+            PaymentError::NotYetIndexed { block_index } => Self::NotYetIndexed { block_index },
+            PaymentError::UnknownMemo => Self::UnknownMemo,
+            PaymentError::NotATransfer => Self::NotATransfer,
+            PaymentError::WrongSender => Self::WrongSender,
+            PaymentError::WrongDestination => Self::WrongDestination {
+                expected: expected.to_hex(),
+            },
+            PaymentError::InsufficientAmount { required, got } => {
+                Self::InsufficientAmount { required, got }
+            }
+        }
+    }
+}
+
+/// The account of the cycles minting canister that tops up the miner, the
+/// destination of top-up payments.
+pub fn top_up_account(miner: Principal) -> AccountIdentifier {
+    AccountIdentifier::new(
+        PrincipalId(MAINNET_CYCLE_MINTER_CANISTER_ID),
+        Some(Subaccount::from(&PrincipalId(miner))),
+    )
+}
+
+/// Converts the ICP payment at `block_index`, sent by `caller` to the
+/// top-up account of the miner, to cycles of the miner. Unused payments to
+/// a spawn or pool payment account are accepted too, so that the caller
+/// gets the cycles of payments the minter cannot use. The caller must hold
+/// its guard.
+pub async fn top_up_miner(
+    caller: Principal,
+    miner: Principal,
+    block_index: u64,
+) -> Result<MinerTopUp, TopUpMinerError> {
+    if get_miner_owner(miner).is_none() {
+        return Err(TopUpMinerError::UnknownMiner);
+    }
+
+    let mut top_up = match get_top_up(miner, block_index) {
+        Some(top_up) if top_up.payer != caller => return Err(TopUpMinerError::WrongSender),
+        Some(top_up) => top_up,
+        None => {
+            let expected = top_up_account(miner);
+            let (amount_e8s, held_by_minter) =
+                match validate_payment(caller, block_index, &[expected], MIN_TOP_UP_E8S).await {
+                    Ok(amount_e8s) => (amount_e8s, false),
+                    Err(PaymentError::WrongDestination) => {
+                        let held_accounts = read_config(|c| {
+                            let mut accounts = c.spawn_payment_accounts();
+                            accounts.push(c.pool_payment_account());
+                            accounts
+                        });
+                        let amount_e8s =
+                            validate_payment(caller, block_index, &held_accounts, MIN_TOP_UP_E8S)
+                                .await
+                                .map_err(|e| TopUpMinerError::from_payment_error(e, expected))?;
+                        (amount_e8s, true)
+                    }
+                    Err(e) => return Err(TopUpMinerError::from_payment_error(e, expected)),
+                };
+            insert_block_index(block_index);
+            let top_up = MinerTopUp {
+                miner,
+                block_index,
+                payer: caller,
+                amount_e8s,
+                created_at: ic_cdk::api::time(),
+                status: TopUpStatus::Pending,
+                held_by_minter,
+            };
+            insert_top_up(top_up.clone());
+            top_up
+        }
+    };
+
+    match top_up.status.clone() {
+        TopUpStatus::ToppedUp { .. } => return Ok(top_up),
+        TopUpStatus::Refunded { reason } => {
+            return Err(TopUpMinerError::PaymentRefunded { reason })
+        }
+        TopUpStatus::Pending | TopUpStatus::Failed { .. } | TopUpStatus::Converted { .. } => {}
+    }
+
+    if let TopUpStatus::Pending | TopUpStatus::Failed { .. } = top_up.status {
+        // Notifying twice returns the outcome of the first notification.
+        let canister_id = if top_up.held_by_minter {
+            ic_cdk::id()
+        } else {
+            miner
+        };
+        top_up.status = match notify_top_up(block_index, canister_id).await {
+            Ok(cycles) if top_up.held_by_minter => TopUpStatus::Converted {
+                cycles: cycles.get(),
+            },
+            Ok(cycles) => TopUpStatus::ToppedUp {
+                cycles: cycles.get(),
+            },
+            Err(NotifyError::Refunded { reason, .. }) => TopUpStatus::Refunded { reason },
+            Err(e) => TopUpStatus::Failed {
+                error: e.to_string(),
+            },
+        };
+        insert_top_up(top_up.clone());
+    }
+
+    if let TopUpStatus::Converted { cycles } = top_up.status {
+        if let Err(e) = deposit_cycles(miner, cycles).await {
+            // The rejected call returned the cycles, the deposit is retried
+            // by the next call.
+            return Err(TopUpMinerError::CyclesTopUpFailed {
+                block_index,
+                error: format!("{e:?}"),
+            });
+        }
+        top_up.status = TopUpStatus::ToppedUp { cycles };
+        insert_top_up(top_up.clone());
+    }
+
+    match top_up.status {
+        TopUpStatus::ToppedUp { cycles } => {
+            process_event(EventType::MinerToppedUp {
+                miner,
+                block_index,
+                amount_e8s: top_up.amount_e8s,
+                cycles,
+            });
+            Ok(top_up)
+        }
+        TopUpStatus::Refunded { reason } => Err(TopUpMinerError::PaymentRefunded { reason }),
+        TopUpStatus::Failed { error } => {
+            Err(TopUpMinerError::CyclesTopUpFailed { block_index, error })
+        }
+        TopUpStatus::Pending | TopUpStatus::Converted { .. } => {
+            unreachable!("bug: top-up attempt without outcome")
+        }
+    }
+}
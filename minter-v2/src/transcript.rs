@@ -0,0 +1,163 @@
+use crate::icrc3::Hash;
+use crate::selection::{select_winner, total_cycles};
+use crate::Block;
+use candid::{CandidType, Nat, Principal};
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Participant {
+    pub miner: Principal,
+    pub burned_cycles: u64,
+    /// Owner of the miner when the round ended, not part of the hash.
+    #[serde(default)]
+    pub owner: Option<Principal>,
+}
+
+/// Everything needed to replay the winner selection of a round: the
+/// randomness returned by `raw_rand` and the participants in the order
+/// they were fed into the lottery.
+#[derive(Clone, CandidType, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct RoundTranscript {
+    pub randomness: Vec<u8>,
+    pub participants: Vec<Participant>,
+}
+
+impl RoundTranscript {
+    pub fn new(randomness: [u8; 32], participants: &[(Principal, u64)]) -> Self {
+        Self {
+            randomness: randomness.to_vec(),
+            participants: participants
+                .iter()
+                .map(|(miner, burned_cycles)| Participant {
+                    miner: *miner,
+                    burned_cycles: *burned_cycles,
+                    owner: None,
+                })
+                .collect(),
+        }
+    }
+
+    /// Records the current owner of each participant.
+    pub fn with_owners(mut self, owner_of: impl Fn(Principal) -> Option<Principal>) -> Self {
+        for participant in &mut self.participants {
+            participant.owner = owner_of(participant.miner);
+        }
+        self
+    }
+
+    pub fn entries(&self) -> Vec<(Principal, u64)> {
+        self.participants
+            .iter()
+            .map(|p| (p.miner, p.burned_cycles))
+            .collect()
+    }
+
+    pub fn total_cycles_burned(&self) -> u64 {
+        total_cycles(&self.entries())
+    }
+
+    /// Representation-independent hash of the ordered participant list,
+    /// see the ICRC-3 value hashing rules.
+    pub fn participants_hash(&self) -> Hash {
+        ICRC3Value::Array(
+            self.participants
+                .iter()
+                .map(|p| {
+                    ICRC3Value::Array(vec![
+                        ICRC3Value::Blob(ByteBuf::from(p.miner.as_slice().to_vec())),
+                        ICRC3Value::Nat(Nat::from(p.burned_cycles)),
+                    ])
+                })
+                .collect(),
+        )
+        .hash()
+    }
+}
+
+/// Replays the winner selection of the given block from its transcript
+/// and checks that it matches what the minter recorded.
+pub fn verify_round(block: &Block, transcript: &RoundTranscript) -> Result<(), String> {
+    if block.randomness.as_ref() != Some(&transcript.randomness) {
+        return Err("randomness does not match the block".to_string());
+    }
+    if block.participants_hash.as_deref() != Some(&transcript.participants_hash()[..]) {
+        return Err("participants do not match the block".to_string());
+    }
+    if block.total_cycles_burned != Some(transcript.total_cycles_burned()) {
+        return Err("total burned cycles do not match the block".to_string());
+    }
+
+    let randomness: [u8; 32] = transcript
+        .randomness
+        .clone()
+        .try_into()
+        .map_err(|_| "randomness must be 32 bytes".to_string())?;
+    let winner = select_winner(&transcript.entries(), randomness)
+        .ok_or_else(|| "no winner can be selected".to_string())?;
+
+    if block.miner != Some(winner) {
+        return Err(format!(
+            "the lottery selects {winner} but the block was mined by {:?}",
+            block.miner
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transcript() -> RoundTranscript {
+        let participants: Vec<(Principal, u64)> = (1..=5_u8)
+            .map(|i| (Principal::from_slice(&[i; 29]), i as u64 * 10_000_000_000))
+            .collect();
+        RoundTranscript::new([42; 32], &participants)
+    }
+
+    fn block_for(transcript: &RoundTranscript) -> Block {
+        let winner =
+            select_winner(&transcript.entries(), [42; 32]).expect("a winner must be selected");
+        Block {
+            to: winner,
+            miner: Some(winner),
+            rewards: 60_000_000_000,
+            timestamp: 1,
+            total_cycles_burned: Some(transcript.total_cycles_burned()),
+            miner_cycles_burned: None,
+            miner_count: Some(transcript.participants.len() as u64),
+            randomness: Some(transcript.randomness.clone()),
+            participants_hash: Some(transcript.participants_hash().to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_verify_round_accepts_honest_selection() {
+        let transcript = transcript();
+        assert_eq!(verify_round(&block_for(&transcript), &transcript), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_round_rejects_tampered_data() {
+        let transcript = transcript();
+        let block = block_for(&transcript);
+
+        let mut other_winner = block.clone();
+        other_winner.miner = transcript
+            .participants
+            .iter()
+            .map(|p| p.miner)
+            .find(|miner| Some(*miner) != block.miner);
+        assert!(verify_round(&other_winner, &transcript).is_err());
+
+        let mut tampered = transcript.clone();
+        tampered.participants[0].burned_cycles += 1;
+        assert!(verify_round(&block, &tampered).is_err());
+
+        let mut tampered = transcript.clone();
+        tampered.randomness[0] ^= 1;
+        assert!(verify_round(&block, &tampered).is_err());
+    }
+}